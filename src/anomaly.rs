@@ -0,0 +1,392 @@
+// Opt-in detection of NaN and infinite numbers. Inside `detect_anomaly`, every op
+// checks its output and every backward rule and gradient hook checks the gradients
+// it produces, and the first non-finite number is reported along with the op that
+// produced it.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::float::Float;
+use crate::id::Id;
+use crate::value::{Value, ValueData};
+
+/// Whether a non-finite number came out of an op, out of its backward rule or out
+/// of a hook registered on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnomalyPhase {
+    Forward,
+    Backward,
+    Hook,
+}
+
+/// Describes the first non-finite number found inside `detect_anomaly`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnomalyError {
+    pub phase: AnomalyPhase,
+    /// The op that produced the number, or whose backward rule did.
+    pub op: String,
+    /// The constant operand stored on the value, if the op has one.
+    pub constant: Option<f64>,
+    /// The data of the op's inputs.
+    pub operands: Vec<f64>,
+    /// The op's output.
+    pub data: f64,
+    /// For backward and hook anomalies, the gradient flowing into the op's output.
+    pub grad: Option<f64>,
+    /// The label of the op's output, if it was given one with `Value::named`.
+    pub label: Option<String>,
+    /// The subgraph, such as a `Neuron`, that the op's output belongs to.
+    pub subgraph_id: Option<Id>,
+    /// The ops leading up to this one, ending with it. For forward anomalies this
+    /// starts at a leaf and follows the input of largest magnitude at each step, and
+    /// for backward anomalies it starts at the value `backward` was called on.
+    pub chain: Vec<String>,
+}
+
+impl fmt::Display for AnomalyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self.constant {
+            Some(constant) => format!("{} {}", self.op, constant),
+            None => self.op.clone(),
+        };
+        match self.phase {
+            AnomalyPhase::Forward => write!(
+                f,
+                "{} produced {} from operands {:?}",
+                op, self.data, self.operands
+            )?,
+            AnomalyPhase::Backward => write!(
+                f,
+                "backward of {} produced a non-finite gradient from operands {:?} and grad {}",
+                op,
+                self.operands,
+                self.grad.unwrap()
+            )?,
+            AnomalyPhase::Hook => write!(
+                f,
+                "a hook on {} returned a non-finite gradient for grad {}",
+                if op.is_empty() { "a leaf" } else { &op },
+                self.grad.unwrap()
+            )?,
+        }
+        if let Some(label) = &self.label {
+            write!(f, " for {:?}", label)?;
+        }
+        if let Some(subgraph_id) = self.subgraph_id {
+            write!(f, " in subgraph {}", subgraph_id)?;
+        }
+        write!(f, " (ops: {})", self.chain.join(" -> "))
+    }
+}
+
+impl std::error::Error for AnomalyError {}
+
+// An anomaly that was found, and how to fill in the parts of it that are only known
+// once the computation is done, such as the subgraph set by `Neuron::forward` or a
+// label given with `Value::named`.
+type Found = (AnomalyError, Box<dyn FnOnce(&mut AnomalyError)>);
+
+thread_local! {
+    static ENABLED: Cell<bool> = const { Cell::new(false) };
+    static FOUND: RefCell<Option<Found>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` with anomaly detection enabled on this thread. Returns an error
+/// describing the first NaN or infinity produced by an op, a backward rule or a
+/// gradient hook inside `f`, if there was one. `f` still runs to completion either
+/// way.
+///
+/// Checking every op is slow, so this is meant for tracking down where a NaN comes
+/// from rather than for every training step.
+#[allow(dead_code)]
+pub fn detect_anomaly<R>(f: impl FnOnce() -> R) -> Result<R, Box<AnomalyError>> {
+    // restores the previous state even if `f` panics, so detection can be nested
+    struct Guard(bool, Option<Found>);
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            ENABLED.with(|enabled| enabled.set(self.0));
+            FOUND.with(|found| *found.borrow_mut() = self.1.take());
+        }
+    }
+
+    let _guard = Guard(
+        ENABLED.with(|enabled| enabled.replace(true)),
+        FOUND.with(|found| found.borrow_mut().take()),
+    );
+    let result = f();
+
+    match FOUND.with(|found| found.borrow_mut().take()) {
+        Some((mut err, finish)) => {
+            finish(&mut err);
+            Err(Box::new(err))
+        }
+        None => Ok(result),
+    }
+}
+
+/// Whether we're inside `detect_anomaly`.
+pub fn is_anomaly_detection_enabled() -> bool {
+    ENABLED.with(|enabled| enabled.get())
+}
+
+fn record(err: AnomalyError, finish: impl FnOnce(&mut AnomalyError) + 'static) {
+    FOUND.with(|found| {
+        let mut found = found.borrow_mut();
+        // later anomalies are usually caused by the first one
+        if found.is_none() {
+            *found = Some((err, Box::new(finish)));
+        }
+    });
+}
+
+fn describe<T: Float>(phase: AnomalyPhase, value: &ValueData<T>) -> AnomalyError {
+    AnomalyError {
+        phase,
+        op: value.op.clone().unwrap_or_default(),
+        constant: value.constant.map(Float::to_f64),
+        operands: value
+            .prev
+            .iter()
+            .map(|prev| prev.borrow().data.to_f64())
+            .collect(),
+        data: value.data.to_f64(),
+        grad: None,
+        label: None,
+        subgraph_id: None,
+        chain: Vec::new(),
+    }
+}
+
+/// Describes the op that produced `value` if its data isn't finite. Called before
+/// `no_grad` strips the op from the value.
+pub(crate) fn check_forward<T: Float>(value: &ValueData<T>) -> Option<AnomalyError> {
+    if !is_anomaly_detection_enabled() || value.op.is_none() || value.data.is_finite() {
+        return None;
+    }
+    Some(describe(AnomalyPhase::Forward, value))
+}
+
+/// Records an anomaly found by `check_forward` once `value` has been created.
+pub(crate) fn record_forward<T: Float>(err: AnomalyError, value: &Value<T>) {
+    let value = value.clone();
+    record(err, move |err| {
+        err.label = value.borrow().label.clone();
+        err.subgraph_id = value.borrow().subgraph_id;
+        err.chain = forward_chain(&value);
+    });
+}
+
+/// The grads of the inputs of `value`, to pass to `check_backward` once its backward
+/// rule has run. `None` outside `detect_anomaly`.
+pub(crate) fn grads_before<T: Float>(value: &Value<T>) -> Option<Vec<T>> {
+    if !is_anomaly_detection_enabled() {
+        return None;
+    }
+    Some(
+        value
+            .borrow()
+            .prev
+            .iter()
+            .map(|prev| prev.borrow().grad)
+            .collect(),
+    )
+}
+
+/// Records an anomaly if the backward rule of `value` just made the grad of any of
+/// its inputs non-finite, during a backward pass started at `root`. Grads that
+/// already weren't finite, e.g. from an earlier pass, aren't blamed on this rule.
+pub(crate) fn check_backward<T: Float>(
+    root: &Value<T>,
+    value: &Value<T>,
+    grads_before: Option<Vec<T>>,
+) {
+    let Some(grads_before) = grads_before else {
+        return;
+    };
+    let v = value.borrow();
+    let broke = v
+        .prev
+        .iter()
+        .zip(grads_before)
+        .any(|(prev, before)| before.is_finite() && !prev.borrow().grad.is_finite());
+    if !broke {
+        return;
+    }
+
+    let mut err = describe(AnomalyPhase::Backward, &v);
+    err.grad = Some(v.grad.to_f64());
+    record_backward(err, root, value);
+}
+
+/// Records an anomaly if the hooks of `value` turned the finite gradient `incoming`
+/// into the non-finite `returned`, during a backward pass started at `root`.
+pub(crate) fn check_hooks<T: Float>(root: &Value<T>, value: &Value<T>, incoming: T, returned: T) {
+    if !is_anomaly_detection_enabled() || !incoming.is_finite() || returned.is_finite() {
+        return;
+    }
+    let mut err = describe(AnomalyPhase::Hook, &value.borrow());
+    err.grad = Some(incoming.to_f64());
+    record_backward(err, root, value);
+}
+
+fn record_backward<T: Float>(err: AnomalyError, root: &Value<T>, value: &Value<T>) {
+    let (root, value) = (root.clone(), value.clone());
+    record(err, move |err| {
+        err.label = value.borrow().label.clone();
+        err.subgraph_id = value.borrow().subgraph_id;
+        err.chain = backward_chain(&root, &value);
+    });
+}
+
+fn op_name<T: Float>(value: &Value<T>) -> Option<String> {
+    value.borrow().op.clone()
+}
+
+fn forward_chain<T: Float>(value: &Value<T>) -> Vec<String> {
+    let mut chain = vec![];
+    let mut current = Some(value.clone());
+    while let Some(value) = current {
+        chain.extend(op_name(&value));
+        current = value
+            .borrow()
+            .prev
+            .iter()
+            .filter(|prev| prev.borrow().op.is_some())
+            .max_by(|a, b| {
+                let a = a.borrow().data.to_f64().abs();
+                let b = b.borrow().data.to_f64().abs();
+                a.total_cmp(&b)
+            })
+            .cloned();
+    }
+    chain.reverse();
+    chain
+}
+
+fn backward_chain<T: Float>(root: &Value<T>, value: &Value<T>) -> Vec<String> {
+    // remember which value each value was first reached from, walking down from root
+    let mut reached_from: HashMap<Value<T>, Value<T>> = HashMap::new();
+    let mut stack = vec![root.clone()];
+    while let Some(current) = stack.pop() {
+        if current == *value {
+            break;
+        }
+        for prev in current.borrow().prev.iter() {
+            if *prev != *root && !reached_from.contains_key(prev) {
+                reached_from.insert(prev.clone(), current.clone());
+                stack.push(prev.clone());
+            }
+        }
+    }
+
+    let mut chain = vec![];
+    let mut current = Some(value.clone());
+    while let Some(value) = current {
+        chain.extend(op_name(&value));
+        current = reached_from.get(&value).cloned();
+    }
+    chain.reverse();
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::neural::Neuron;
+
+    #[test]
+    fn forward_anomaly() {
+        let a = Value::from(-1.0);
+        let b = Value::from(2.0);
+
+        let err = detect_anomaly(|| {
+            let c = (&a * &b).exp();
+            let d = (&c - 3.0).sqrt().named("d") + &b;
+            d.backward();
+        })
+        .unwrap_err();
+
+        assert_eq!(err.phase, AnomalyPhase::Forward);
+        assert_eq!(err.op, "sqrt()");
+        assert!(err.data.is_nan());
+        assert_eq!(err.operands.len(), 1);
+        assert_eq!(err.chain, vec!["*", "exp()", "-", "sqrt()"]);
+        assert_eq!(err.label.as_deref(), Some("d"));
+        assert!(err.to_string().contains("for \"d\""));
+    }
+
+    #[test]
+    fn backward_anomaly() {
+        // sqrt has an infinite slope at zero, though its output is finite
+        let a: Value = Value::from(0.0);
+
+        let err = detect_anomaly(|| {
+            let b = a.sqrt() * 2.0;
+            b.backward();
+        })
+        .unwrap_err();
+
+        assert_eq!(err.phase, AnomalyPhase::Backward);
+        assert_eq!(err.op, "sqrt()");
+        assert_eq!(err.operands, vec![0.0]);
+        assert_eq!(err.grad, Some(2.0));
+        assert_eq!(err.chain, vec!["*", "sqrt()"]);
+        assert!(a.borrow().grad.is_infinite());
+    }
+
+    #[test]
+    fn earlier_nan_not_blamed() {
+        let a: Value = Value::from(0.0);
+        a.sqrt().backward();
+        assert!(a.borrow().grad.is_infinite());
+
+        // a's grad is already infinite, but nothing in this pass made it so
+        let result = detect_anomaly(|| (&a * 2.0).tanh().backward());
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn hook_anomaly() {
+        let a = Value::from(2.0);
+        let b = (&a * 3.0).named("b");
+        b.register_hook(|grad| grad / 0.0);
+
+        let err = detect_anomaly(|| b.tanh().backward()).unwrap_err();
+
+        assert_eq!(err.phase, AnomalyPhase::Hook);
+        assert_eq!(err.op, "*");
+        assert_eq!(err.label.as_deref(), Some("b"));
+        assert_eq!(err.chain, vec!["tanh()", "*"]);
+        assert!(err.to_string().starts_with("a hook on * 3 returned"));
+    }
+
+    #[test]
+    fn subgraph() {
+        let neuron = Neuron::new(2);
+        let err = detect_anomaly(|| {
+            neuron.forward(vec![
+                Value::from(f64::INFINITY),
+                Value::from(f64::NEG_INFINITY),
+            ])
+        })
+        .unwrap_err();
+
+        assert_eq!(err.subgraph_id, neuron.subgraph_id);
+        assert!(err.to_string().contains("in subgraph"));
+    }
+
+    #[test]
+    fn only_inside_detect_anomaly() {
+        let a = Value::from(-1.0);
+        assert_eq!(
+            detect_anomaly(|| a.exp().borrow().data),
+            Ok((-1.0_f64).exp())
+        );
+
+        // nothing is recorded outside, so it can't leak into a later call
+        a.ln();
+        assert!(detect_anomaly(|| a.exp()).is_ok());
+        assert!(!is_anomaly_detection_enabled());
+    }
+}
@@ -0,0 +1,319 @@
+// Traces a graph once into a flat list of instructions over numbered slots, which
+// can then be rerun forward and backward for new input data without building any
+// new values. Useful when the same graph shape is evaluated many times, e.g. once
+// per training step.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use crate::float::Float;
+use crate::op::{checked_backward, Op};
+use crate::rule::Rule;
+use crate::value::{GraphFreedError, Value};
+
+enum Instruction<T: Float> {
+    Builtin(Rule<T>),
+    Custom(Rc<dyn Op<T>>),
+}
+
+/// Computes the value in slot `output` from the values in the `inputs` slots.
+struct Step<T: Float> {
+    instruction: Instruction<T>,
+    inputs: Vec<usize>,
+    output: usize,
+}
+
+/// A graph traced by `compile`. Every value of the graph has a slot holding its data
+/// and grad, in topological order.
+pub struct CompiledGraph<T: Float = f64> {
+    steps: Vec<Step<T>>,
+    /// The slot of each input passed to `compile`, or `None` if the root doesn't
+    /// depend on it.
+    inputs: Vec<Option<usize>>,
+    /// Every other leaf, such as a model's parameters, with its slot.
+    parameters: Vec<(usize, Value<T>)>,
+    root: usize,
+    data: RefCell<Vec<T>>,
+    grad: RefCell<Vec<T>>,
+    /// Scratch space for the data of one step's inputs.
+    scratch: RefCell<Vec<T>>,
+}
+
+/// Traces the graph ending at `root` into a `CompiledGraph` that evaluates it for
+/// new data of `inputs`.
+///
+/// Every other leaf, e.g. a model's parameters, is read from its `Value` on every
+/// call, so changes to their data are seen by the next call. Hooks aren't run.
+///
+/// Panics if any of `inputs` isn't a leaf, as its data would be computed by its op
+/// rather than given to the `CompiledGraph`, or if part of the graph was freed by
+/// `backward_and_free`.
+#[allow(dead_code)]
+pub fn compile<T: Float>(root: &Value<T>, inputs: &[Value<T>]) -> CompiledGraph<T> {
+    for input in inputs.iter() {
+        assert!(
+            input.is_leaf(),
+            "the inputs of a compiled graph must be leaves"
+        );
+    }
+
+    let topo = root.build_topo();
+    let slots: HashMap<Value<T>, usize> = topo
+        .iter()
+        .enumerate()
+        .map(|(slot, value)| (value.clone(), slot))
+        .collect();
+
+    let input_slots: Vec<Option<usize>> = inputs
+        .iter()
+        .map(|input| slots.get(input).copied())
+        .collect();
+
+    let mut steps = vec![];
+    let mut parameters = vec![];
+    for (slot, value) in topo.iter().enumerate() {
+        let v = value.borrow();
+        if v.freed {
+            panic!("{}", GraphFreedError);
+        }
+        if v.prev.is_empty() {
+            if !input_slots.contains(&Some(slot)) {
+                parameters.push((slot, value.clone()));
+            }
+            continue;
+        }
+
+        let instruction = match &v.custom_op {
+            Some(op) => Instruction::Custom(op.clone()),
+            None => {
+                let op = v.op.as_deref().unwrap();
+                let rule = Rule::new(op, v.prev.len(), v.constant)
+                    .unwrap_or_else(|| panic!("can't compile op {:?}", op));
+                Instruction::Builtin(rule)
+            }
+        };
+        steps.push(Step {
+            instruction,
+            inputs: v.prev.iter().map(|prev| slots[prev]).collect(),
+            output: slot,
+        });
+    }
+
+    let max_inputs = steps
+        .iter()
+        .map(|step| step.inputs.len())
+        .max()
+        .unwrap_or(0);
+    CompiledGraph {
+        steps,
+        inputs: input_slots,
+        parameters,
+        root: slots[root],
+        data: RefCell::new(vec![T::ZERO; topo.len()]),
+        grad: RefCell::new(vec![T::ZERO; topo.len()]),
+        scratch: RefCell::new(Vec::with_capacity(max_inputs)),
+    }
+}
+
+#[allow(dead_code)]
+impl<T: Float> CompiledGraph<T> {
+    /// Runs the graph forward with `inputs` as the data of the inputs passed to
+    /// `compile`, in the same order, and returns the root's data.
+    pub fn eval(&self, inputs: &[T]) -> T {
+        assert_eq!(
+            inputs.len(),
+            self.inputs.len(),
+            "expected {} inputs",
+            self.inputs.len()
+        );
+
+        let mut data = self.data.borrow_mut();
+        let mut scratch = self.scratch.borrow_mut();
+        for (slot, input) in self.inputs.iter().zip(inputs) {
+            if let Some(slot) = slot {
+                data[*slot] = *input;
+            }
+        }
+        for (slot, parameter) in self.parameters.iter() {
+            data[*slot] = parameter.borrow().data;
+        }
+
+        for step in self.steps.iter() {
+            scratch.clear();
+            scratch.extend(step.inputs.iter().map(|&input| data[input]));
+            data[step.output] = match &step.instruction {
+                Instruction::Builtin(rule) => rule.forward(&scratch),
+                Instruction::Custom(op) => op.forward(&scratch),
+            };
+        }
+
+        data[self.root]
+    }
+
+    /// Like `eval`, then backpropagates from the root. Returns the gradient of each
+    /// input, and adds the gradients of the other leaves to their `Value`'s `grad`
+    /// like `Value::backward` does.
+    pub fn grad(&self, inputs: &[T]) -> Vec<T> {
+        self.eval(inputs);
+
+        let data = self.data.borrow();
+        let mut grad = self.grad.borrow_mut();
+        let mut scratch = self.scratch.borrow_mut();
+        grad.iter_mut().for_each(|grad| *grad = T::ZERO);
+        grad[self.root] = T::ONE;
+
+        for step in self.steps.iter().rev() {
+            scratch.clear();
+            scratch.extend(step.inputs.iter().map(|&input| data[input]));
+            let out = data[step.output];
+            let out_grad = grad[step.output];
+            match &step.instruction {
+                Instruction::Builtin(rule) => {
+                    rule.backward(&scratch, out, out_grad, |i, g| grad[step.inputs[i]] += g)
+                }
+                Instruction::Custom(op) => {
+                    let grads = checked_backward(op.as_ref(), &scratch, out, out_grad);
+                    for (i, g) in grads.into_iter().enumerate() {
+                        grad[step.inputs[i]] += g;
+                    }
+                }
+            }
+        }
+
+        for (slot, parameter) in self.parameters.iter() {
+            parameter.borrow_mut().grad += grad[*slot];
+        }
+        self.inputs
+            .iter()
+            .map(|slot| slot.map_or(T::ZERO, |slot| grad[slot]))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::neural::MLP;
+
+    #[test]
+    fn matches_backward() {
+        let a = Value::from(1.5);
+        let b = Value::from(0.7);
+        let c = Value::from(-0.4);
+        let f = (&a * &b + &c).tanh() / (&a - &c).powi(2)
+            + a.pow(b.clone())
+            + b.sigmoid() * c.exp()
+            + (&a * &c).sin().abs()
+            + b.softplus().ln()
+            + c.gelu().max(&c.silu())
+            + 2.0 / a.leaky_relu(0.1).sqrt()
+            + [a.clone(), b.clone(), c.clone()]
+                .iter()
+                .sum::<Value>()
+                .clamp(-1.0, 1.0);
+        f.backward();
+
+        let compiled = compile(&f, &[a.clone(), b.clone(), c.clone()]);
+        let grads = compiled.grad(&[1.5, 0.7, -0.4]);
+
+        assert_eq!(compiled.eval(&[1.5, 0.7, -0.4]), f.borrow().data);
+        for (grad, input) in grads.iter().zip([&a, &b, &c]) {
+            assert!((grad - input.borrow().grad).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn every_op() {
+        use crate::value::OP_EXAMPLES;
+
+        for &(op, arity, constant) in OP_EXAMPLES {
+            let inputs: Vec<Value> = [0.7, 1.3, -0.4][..arity]
+                .iter()
+                .map(|&x| Value::from(x))
+                .collect();
+            let value = Value::from_op(op, &inputs, constant).unwrap();
+            value.backward();
+
+            let compiled = compile(&value, &inputs);
+            let data: Vec<f64> = inputs.iter().map(|input| input.borrow().data).collect();
+            let grads = compiled.grad(&data);
+
+            assert_eq!(compiled.eval(&data), value.borrow().data, "{}", op);
+            for (grad, input) in grads.iter().zip(inputs.iter()) {
+                assert!((grad - input.borrow().grad).abs() < 1e-12, "{}", op);
+            }
+        }
+    }
+
+    #[test]
+    fn f32_graph() {
+        let a = Value::<f32>::from(1.5);
+        let b = Value::<f32>::from(-0.5);
+        let f = (&a * &b).tanh() + a.gelu() * b.exp();
+        f.backward();
+
+        let compiled = compile(&f, &[a.clone(), b.clone()]);
+        let grads: Vec<f32> = compiled.grad(&[1.5, -0.5]);
+
+        assert_eq!(compiled.eval(&[1.5, -0.5]), f.borrow().data);
+        assert_eq!(grads, vec![a.borrow().grad, b.borrow().grad]);
+    }
+
+    #[test]
+    #[should_panic(expected = "must be leaves")]
+    fn inputs_must_be_leaves() {
+        let x = Value::from(2.0);
+        let h = &x * 3.0;
+        compile(&h.tanh(), &[x, h]);
+    }
+
+    #[test]
+    fn new_inputs() {
+        let x = Value::from(1.0);
+        let y = Value::from(2.0);
+        let unused = Value::from(5.0);
+        let f = &x * &y + x.exp();
+
+        let compiled = compile(&f, &[x, y, unused]);
+
+        assert_eq!(compiled.eval(&[3.0, 4.0, 0.0]), 12.0 + 3.0_f64.exp());
+        assert_eq!(
+            compiled.grad(&[3.0, 4.0, 0.0]),
+            vec![4.0 + 3.0_f64.exp(), 3.0, 0.0]
+        );
+        assert_eq!(compiled.eval(&[0.0, 1.0, 0.0]), 1.0);
+    }
+
+    #[test]
+    fn training_step() {
+        let mlp = MLP::new(3, vec![4, 4, 1]);
+        let x: Vec<Value> = [2.0, 3.0, -1.0].iter().map(|&x| Value::from(x)).collect();
+        let y = Value::from(1.0);
+        let loss = (mlp.forward(x.clone())[0].clone() - &y).powi(2);
+
+        let mut inputs = x.clone();
+        inputs.push(y);
+        let compiled = compile(&loss, &inputs);
+
+        // parameter grads land on the parameters, and parameter updates are picked up
+        for _ in 0..3 {
+            mlp.zero_grad();
+            compiled.grad(&[3.0, -1.0, 0.5, -1.0]);
+            let compiled_grads: Vec<f64> =
+                mlp.parameters().iter().map(|p| p.borrow().grad).collect();
+
+            mlp.zero_grad();
+            let x = [3.0, -1.0, 0.5].iter().map(|&x| Value::from(x)).collect();
+            let loss = (mlp.forward(x)[0].clone() + 1.0).powi(2);
+            loss.backward();
+            assert_eq!(compiled.eval(&[3.0, -1.0, 0.5, -1.0]), loss.borrow().data);
+
+            for (param, grad) in mlp.parameters().iter().zip(compiled_grads) {
+                assert!((param.borrow().grad - grad).abs() < 1e-12);
+                let update = -0.1 * param.borrow().grad;
+                param.borrow_mut().data += update;
+            }
+        }
+    }
+}
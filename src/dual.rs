@@ -0,0 +1,255 @@
+// Forward mode automatic differentiation. A `Dual` carries a value together with
+// its derivative along one input direction (its tangent), so a single forward pass
+// gives the derivative of every output along that direction without building a
+// graph. This is cheaper than `Value::backward` for functions with few inputs and
+// many outputs, e.g. the sensitivity of all of a model's outputs to one input.
+
+use std::iter::Sum;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+use crate::neural::Scalar;
+use crate::rule::powi_slope;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Dual {
+    pub data: f64,
+    /// The derivative of `data` along the chosen input direction.
+    pub tangent: f64,
+}
+
+impl Dual {
+    pub fn new(data: f64, tangent: f64) -> Dual {
+        Dual { data, tangent }
+    }
+
+    /// A value that doesn't depend on the input direction, such as a parameter.
+    pub fn constant(data: f64) -> Dual {
+        Dual::new(data, 0.0)
+    }
+
+    /// The input being differentiated with respect to.
+    pub fn variable(data: f64) -> Dual {
+        Dual::new(data, 1.0)
+    }
+
+    /// Raises `self` to the power of `n`. Like `Value::pow`, the part of the
+    /// derivative that flows through the exponent is taken to be `0` for a base of
+    /// zero and is NaN for negative bases.
+    pub fn pow(&self, n: Dual) -> Dual {
+        let data = self.data.powf(n.data);
+        let exp_part = if n.tangent == 0.0 || self.data == 0.0 {
+            0.0
+        } else {
+            n.tangent * self.data.ln() * data
+        };
+        Dual::new(
+            data,
+            self.tangent * n.data * self.data.powf(n.data - 1.0) + exp_part,
+        )
+    }
+
+    pub fn powi(&self, n: i32) -> Dual {
+        Dual::new(self.data.powi(n), self.tangent * powi_slope(self.data, n))
+    }
+
+    pub fn relu(&self) -> Dual {
+        if self.data > 0.0 {
+            *self
+        } else {
+            Dual::constant(0.0)
+        }
+    }
+
+    pub fn tanh(&self) -> Dual {
+        let tanh = self.data.tanh();
+        Dual::new(tanh, self.tangent * (1.0 - tanh * tanh))
+    }
+
+    pub fn exp(&self) -> Dual {
+        let exp = self.data.exp();
+        Dual::new(exp, self.tangent * exp)
+    }
+}
+
+impl From<f64> for Dual {
+    fn from(data: f64) -> Dual {
+        Dual::constant(data)
+    }
+}
+
+impl Add for Dual {
+    type Output = Dual;
+
+    fn add(self, other: Dual) -> Dual {
+        Dual::new(self.data + other.data, self.tangent + other.tangent)
+    }
+}
+
+impl Sub for Dual {
+    type Output = Dual;
+
+    fn sub(self, other: Dual) -> Dual {
+        Dual::new(self.data - other.data, self.tangent - other.tangent)
+    }
+}
+
+impl Mul for Dual {
+    type Output = Dual;
+
+    fn mul(self, other: Dual) -> Dual {
+        Dual::new(
+            self.data * other.data,
+            self.tangent * other.data + self.data * other.tangent,
+        )
+    }
+}
+
+impl Div for Dual {
+    type Output = Dual;
+
+    fn div(self, other: Dual) -> Dual {
+        Dual::new(
+            self.data / other.data,
+            (self.tangent * other.data - self.data * other.tangent) / (other.data * other.data),
+        )
+    }
+}
+
+impl Neg for Dual {
+    type Output = Dual;
+
+    fn neg(self) -> Dual {
+        Dual::new(-self.data, -self.tangent)
+    }
+}
+
+// `Dual`s are `Copy`, so these only exist to satisfy `Scalar`'s bounds
+impl Add<&Dual> for Dual {
+    type Output = Dual;
+
+    fn add(self, other: &Dual) -> Dual {
+        self + *other
+    }
+}
+
+impl Mul<&Dual> for &Dual {
+    type Output = Dual;
+
+    fn mul(self, other: &Dual) -> Dual {
+        *self * *other
+    }
+}
+
+// Arithmetic with `f64` constants in both orders, as for `Value`.
+macro_rules! scalar_binop {
+    ($imp:ident, $method:ident) => {
+        impl $imp<f64> for Dual {
+            type Output = Dual;
+
+            fn $method(self, other: f64) -> Dual {
+                $imp::$method(self, Dual::constant(other))
+            }
+        }
+
+        impl $imp<Dual> for f64 {
+            type Output = Dual;
+
+            fn $method(self, other: Dual) -> Dual {
+                $imp::$method(Dual::constant(self), other)
+            }
+        }
+    };
+}
+
+scalar_binop!(Add, add);
+scalar_binop!(Sub, sub);
+scalar_binop!(Mul, mul);
+scalar_binop!(Div, div);
+
+impl Sum for Dual {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.fold(Dual::constant(0.0), |sum, dual| sum + dual)
+    }
+}
+
+impl Scalar for Dual {
+    fn data(&self) -> f64 {
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::neural::MLP;
+    use crate::Value;
+
+    #[test]
+    fn ops() {
+        // the same function on duals and values, differentiated with respect to `a`
+        let a = Dual::variable(3.0);
+        let b = Dual::constant(4.0);
+        let c = (a * b + a / b - b).tanh() * 2.0 + (-a).powi(2) - a.exp() / 10.0
+            + a.pow(Dual::constant(1.5))
+            + (1.0 - a).relu();
+
+        let va = Value::from(3.0);
+        let vb = Value::from(4.0);
+        let vc = (&va * &vb + &va / &vb - &vb).tanh() * 2.0 + (-&va).powi(2) - va.exp() / 10.0
+            + va.pow(Value::from(1.5))
+            + (1.0 - &va).relu();
+        vc.backward();
+
+        assert!((c.data - vc.borrow().data).abs() < 1e-12);
+        assert!((c.tangent - va.borrow().grad).abs() < 1e-12);
+    }
+
+    #[test]
+    fn pow_exponent() {
+        let a = Dual::constant(3.0);
+        let b = Dual::variable(2.0);
+        let c = a.pow(b);
+
+        assert_eq!(c.data, 9.0);
+        assert_eq!(c.tangent, 3.0_f64.ln() * 9.0);
+    }
+
+    #[test]
+    fn powi_zero_at_zero() {
+        let c = Dual::variable(0.0).powi(0);
+
+        assert_eq!(c.data, 1.0);
+        assert_eq!(c.tangent, 0.0);
+    }
+
+    #[test]
+    fn mlp_jvp() {
+        let mlp = MLP::new(3, vec![4, 4, 2]);
+        let x = [2.0, 3.0, -1.0];
+
+        // the sensitivity of both outputs to the second input in one forward pass
+        let dual_mlp = mlp.snapshot().build(Dual::constant);
+        let inputs = x
+            .iter()
+            .enumerate()
+            .map(|(i, &x)| {
+                if i == 1 {
+                    Dual::variable(x)
+                } else {
+                    Dual::constant(x)
+                }
+            })
+            .collect();
+        let outputs = dual_mlp.forward(inputs);
+
+        // compare against one backward pass per output
+        for (i, output) in outputs.iter().enumerate() {
+            let inputs: Vec<Value> = x.iter().map(|&x| Value::from(x)).collect();
+            let value_output = mlp.forward(inputs.clone())[i].clone();
+            value_output.backward();
+
+            assert!((output.data - value_output.borrow().data).abs() < 1e-12);
+            assert!((output.tangent - inputs[1].borrow().grad).abs() < 1e-12);
+        }
+    }
+}
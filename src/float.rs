@@ -0,0 +1,157 @@
+// The floating point types that `Value` can hold. `f64` is the default, and `f32`
+// halves the memory of every node at the cost of precision.
+
+use std::fmt::{Debug, Display};
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+
+pub trait Float:
+    Copy
+    + Debug
+    + Display
+    + PartialOrd
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+    + AddAssign
+    + SubAssign
+    + Sum
+    + 'static
+{
+    const ZERO: Self;
+    const ONE: Self;
+    const LN_2: Self;
+
+    /// Converts from `f64`, rounding to the nearest representable value.
+    fn from_f64(x: f64) -> Self;
+    fn to_f64(self) -> f64;
+
+    fn exp(self) -> Self;
+    fn exp_m1(self) -> Self;
+    fn ln(self) -> Self;
+    fn ln_1p(self) -> Self;
+    fn log2(self) -> Self;
+    fn sqrt(self) -> Self;
+    fn tanh(self) -> Self;
+    fn sin(self) -> Self;
+    fn cos(self) -> Self;
+    fn abs(self) -> Self;
+    fn powi(self, n: i32) -> Self;
+    fn powf(self, n: Self) -> Self;
+    fn max(self, other: Self) -> Self;
+    fn min(self, other: Self) -> Self;
+    fn is_finite(self) -> bool;
+}
+
+macro_rules! impl_float {
+    ($t:ident) => {
+        impl Float for $t {
+            const ZERO: $t = 0.0;
+            const ONE: $t = 1.0;
+            const LN_2: $t = std::$t::consts::LN_2;
+
+            fn from_f64(x: f64) -> $t {
+                x as $t
+            }
+
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+
+            fn exp(self) -> $t {
+                $t::exp(self)
+            }
+
+            fn exp_m1(self) -> $t {
+                $t::exp_m1(self)
+            }
+
+            fn ln(self) -> $t {
+                $t::ln(self)
+            }
+
+            fn ln_1p(self) -> $t {
+                $t::ln_1p(self)
+            }
+
+            fn log2(self) -> $t {
+                $t::log2(self)
+            }
+
+            fn sqrt(self) -> $t {
+                $t::sqrt(self)
+            }
+
+            fn tanh(self) -> $t {
+                $t::tanh(self)
+            }
+
+            fn sin(self) -> $t {
+                $t::sin(self)
+            }
+
+            fn cos(self) -> $t {
+                $t::cos(self)
+            }
+
+            fn abs(self) -> $t {
+                $t::abs(self)
+            }
+
+            fn powi(self, n: i32) -> $t {
+                $t::powi(self, n)
+            }
+
+            fn powf(self, n: $t) -> $t {
+                $t::powf(self, n)
+            }
+
+            fn max(self, other: $t) -> $t {
+                $t::max(self, other)
+            }
+
+            fn min(self, other: $t) -> $t {
+                $t::min(self, other)
+            }
+
+            fn is_finite(self) -> bool {
+                $t::is_finite(self)
+            }
+        }
+    };
+}
+
+impl_float!(f32);
+impl_float!(f64);
+
+#[cfg(test)]
+mod tests {
+    use crate::neural::MLP;
+    use crate::Value;
+
+    #[test]
+    fn mlp_f32_matches_f64() {
+        let mlp = MLP::new(3, vec![4, 4, 1]);
+        let mlp_f32 = mlp.snapshot().build(|x| Value::from(x as f32));
+        let xs = [2.0, 3.0, -1.0];
+
+        let out = mlp.forward(xs.iter().map(|&x| Value::from(x)).collect())[0].tanh();
+        let loss = (out - 1.0).powi(2);
+        loss.backward();
+
+        let out_f32 =
+            mlp_f32.forward(xs.iter().map(|&x| Value::from(x as f32)).collect())[0].tanh();
+        let loss_f32 = (out_f32 - 1.0).powi(2);
+        loss_f32.backward();
+
+        assert!((f64::from(loss_f32.borrow().data) - loss.borrow().data).abs() < 1e-5);
+        for (param, param_f32) in mlp.parameters().iter().zip(mlp_f32.parameters().iter()) {
+            assert!((f64::from(param_f32.borrow().grad) - param.borrow().grad).abs() < 1e-5);
+        }
+
+        mlp_f32.zero_grad();
+        assert_eq!(mlp_f32.parameters()[0].borrow().grad, 0.0);
+    }
+}
@@ -0,0 +1,167 @@
+// Derivatives of whole functions rather than of a single scalar `Value`. Each
+// function builds its own leaves from the given input data and takes care of
+// resetting grads between backward passes, restoring them afterwards so that e.g.
+// the accumulated grads of a model's parameters are left as they were.
+
+use crate::Value;
+
+fn leaves(inputs: &[f64]) -> Vec<Value> {
+    inputs.iter().map(|&x| Value::from(x)).collect()
+}
+
+/// Backpropagates from `output` with every grad it depends on reset first, and
+/// returns the resulting grads of `leaves`. Every grad in the graph is put back
+/// afterwards.
+fn clean_backward(output: &Value, leaves: &[Value]) -> Vec<f64> {
+    let values = output.build_topo();
+    let saved_grads: Vec<f64> = values.iter().map(|v| v.borrow().grad).collect();
+
+    // leaves that `output` doesn't depend on aren't reached by `zero_grad`
+    for leaf in leaves.iter() {
+        leaf.borrow_mut().grad = 0.0;
+    }
+    output.zero_grad();
+    output.backward();
+    let grads = leaves.iter().map(|leaf| leaf.borrow().grad).collect();
+
+    for (value, grad) in values.iter().zip(saved_grads) {
+        value.borrow_mut().grad = grad;
+    }
+    grads
+}
+
+/// Returns the Jacobian of `f` at `inputs`, where row `i` holds the gradient of
+/// the `i`th output with respect to every input.
+#[allow(dead_code)]
+pub fn jacobian(f: impl Fn(&[Value]) -> Vec<Value>, inputs: &[f64]) -> Vec<Vec<f64>> {
+    let leaves = leaves(inputs);
+    let outputs = f(&leaves);
+
+    outputs
+        .iter()
+        .map(|output| clean_backward(output, &leaves))
+        .collect()
+}
+
+/// Returns the Hessian of the scalar function `f` at `inputs`, the matrix of its
+/// second derivatives with respect to every pair of inputs.
+#[allow(dead_code)]
+pub fn hessian(f: impl Fn(&[Value]) -> Value, inputs: &[f64]) -> Vec<Vec<f64>> {
+    let leaves = leaves(inputs);
+    let grads = f(&leaves).grad_graph(&leaves);
+
+    grads
+        .iter()
+        .map(|grad| clean_backward(grad, &leaves))
+        .collect()
+}
+
+/// Returns the product of the Hessian of the scalar function `f` at `inputs` with
+/// the vector `v`, without computing the full Hessian.
+#[allow(dead_code)]
+pub fn hvp(f: impl Fn(&[Value]) -> Value, inputs: &[f64], v: &[f64]) -> Vec<f64> {
+    assert_eq!(inputs.len(), v.len());
+
+    let leaves = leaves(inputs);
+    let grads = f(&leaves).grad_graph(&leaves);
+    let grad_dot_v: Value = grads.iter().zip(v.iter()).map(|(grad, &v)| grad * v).sum();
+
+    clean_backward(&grad_dot_v, &leaves)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dual::Dual;
+    use crate::neural::MLP;
+
+    // f(x, y) = x^2 y + y^3, with Hessian [[2y, 2x], [2x, 6y]]
+    fn cubic(x: &[Value]) -> Value {
+        x[0].powi(2) * &x[1] + x[1].powi(3)
+    }
+
+    #[test]
+    fn jacobian_shared_nodes() {
+        let j = jacobian(
+            |x| {
+                let ab = &x[0] * &x[1];
+                vec![ab.clone(), ab.tanh() + &x[0], x[2].clone()]
+            },
+            &[2.0, 3.0, 4.0],
+        );
+
+        let d_tanh = 1.0 - 6.0_f64.tanh().powi(2);
+        assert_eq!(j[0], vec![3.0, 2.0, 0.0]);
+        assert_eq!(j[1], vec![3.0 * d_tanh + 1.0, 2.0 * d_tanh, 0.0]);
+        assert_eq!(j[2], vec![0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn jacobian_mlp() {
+        let mlp = MLP::new(3, vec![4, 2]);
+        let x = [0.5, -1.0, 2.0];
+
+        let j = jacobian(|inputs| mlp.forward(inputs.to_vec()), &x);
+
+        // each column is a forward mode pass along one input
+        let dual_mlp = mlp.snapshot().build(Dual::constant);
+        let columns: Vec<Vec<f64>> = (0..x.len())
+            .map(|col| {
+                let inputs = x
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &x)| Dual::new(x, if i == col { 1.0 } else { 0.0 }))
+                    .collect();
+                dual_mlp
+                    .forward(inputs)
+                    .iter()
+                    .map(|output| output.tangent)
+                    .collect()
+            })
+            .collect();
+
+        for (row, j_row) in j.iter().enumerate() {
+            for (col, &derivative) in j_row.iter().enumerate() {
+                assert!((derivative - columns[col][row]).abs() < 1e-12);
+            }
+        }
+    }
+
+    #[test]
+    fn parameter_grads_untouched() {
+        let mlp = MLP::new(3, vec![4, 2]);
+        for param in mlp.parameters() {
+            param.borrow_mut().grad = 42.0;
+        }
+
+        jacobian(|x| mlp.forward(x.to_vec()), &[0.5, -1.0, 2.0]);
+        hessian(|x| mlp.forward(x.to_vec())[0].tanh(), &[0.5, -1.0, 2.0]);
+
+        for param in mlp.parameters() {
+            assert_eq!(param.borrow().grad, 42.0);
+        }
+    }
+
+    #[test]
+    fn hessian_cubic() {
+        let h = hessian(cubic, &[2.0, 3.0]);
+
+        assert_eq!(h, vec![vec![6.0, 4.0], vec![4.0, 18.0]]);
+    }
+
+    #[test]
+    fn hessian_tanh() {
+        // d^2/dx^2 tanh(x) = -2 tanh(x) (1 - tanh(x)^2)
+        let h = hessian(|x| x[0].tanh(), &[0.5]);
+
+        let t = 0.5_f64.tanh();
+        assert!((h[0][0] - -2.0 * t * (1.0 - t * t)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn hvp_cubic() {
+        let hv = hvp(cubic, &[2.0, 3.0], &[1.0, -1.0]);
+
+        assert_eq!(hv, vec![6.0 - 4.0, 4.0 - 18.0]);
+    }
+}
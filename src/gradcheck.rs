@@ -0,0 +1,218 @@
+// Checks the gradients computed by `Value::backward` against central finite
+// differences, to catch wrong backward rules before they show up as diverging
+// training runs.
+
+use crate::value::no_grad;
+use crate::Value;
+
+/// How far the gradient of one input or parameter was from its finite difference
+/// estimate.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub struct GradError {
+    /// The index of the input or parameter.
+    pub index: usize,
+    /// The gradient computed by `backward`.
+    pub analytic: f64,
+    /// The central finite difference estimate of the gradient.
+    pub numeric: f64,
+    pub abs_error: f64,
+    pub rel_error: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct GradCheckReport {
+    /// One entry per input or parameter, in the order they were passed in.
+    pub errors: Vec<GradError>,
+}
+
+#[allow(dead_code)]
+impl GradCheckReport {
+    pub fn max_abs_error(&self) -> f64 {
+        self.errors.iter().map(|e| e.abs_error).fold(0.0, f64::max)
+    }
+
+    pub fn max_rel_error(&self) -> f64 {
+        self.errors.iter().map(|e| e.rel_error).fold(0.0, f64::max)
+    }
+
+    /// The entry with the largest relative error.
+    pub fn worst(&self) -> Option<&GradError> {
+        self.errors
+            .iter()
+            .max_by(|a, b| a.rel_error.total_cmp(&b.rel_error))
+    }
+
+    /// Whether every gradient is within `abs_tol` or `rel_tol` of its estimate.
+    pub fn passes(&self, abs_tol: f64, rel_tol: f64) -> bool {
+        self.errors
+            .iter()
+            .all(|e| e.abs_error <= abs_tol || e.rel_error <= rel_tol)
+    }
+}
+
+impl GradError {
+    fn new(index: usize, analytic: f64, numeric: f64) -> GradError {
+        let abs_error = (analytic - numeric).abs();
+        let scale = analytic.abs().max(numeric.abs());
+        let rel_error = if scale == 0.0 { 0.0 } else { abs_error / scale };
+        GradError {
+            index,
+            analytic,
+            numeric,
+            abs_error,
+            rel_error,
+        }
+    }
+}
+
+/// Checks the gradients of `f` with respect to each of its inputs, evaluated at
+/// `inputs`, using a finite difference step of `eps`.
+#[allow(dead_code)]
+pub fn gradcheck(f: impl Fn(&[Value]) -> Value, inputs: &[f64], eps: f64) -> GradCheckReport {
+    let leaves: Vec<Value> = inputs.iter().map(|&x| Value::from(x)).collect();
+    f(&leaves).backward();
+
+    let eval = |inputs: &[f64]| {
+        let leaves: Vec<Value> = inputs.iter().map(|&x| Value::from(x)).collect();
+        let out = no_grad(|| f(&leaves));
+        let data = out.borrow().data;
+        data
+    };
+
+    let errors = leaves
+        .iter()
+        .enumerate()
+        .map(|(i, leaf)| {
+            let mut shifted = inputs.to_vec();
+            shifted[i] = inputs[i] + eps;
+            let plus = eval(&shifted);
+            shifted[i] = inputs[i] - eps;
+            let minus = eval(&shifted);
+
+            GradError::new(i, leaf.borrow().grad, (plus - minus) / (2.0 * eps))
+        })
+        .collect();
+
+    GradCheckReport { errors }
+}
+
+/// Checks the gradients of the value built by `loss` with respect to `params`, e.g.
+/// a model's `parameters()`. `loss` is called once per perturbation, so it must
+/// rebuild its graph from the current parameter data each time.
+///
+/// The grads of `params` are restored afterwards, so this can be run in the middle
+/// of training.
+#[allow(dead_code)]
+pub fn gradcheck_params(params: &[Value], loss: impl Fn() -> Value, eps: f64) -> GradCheckReport {
+    let saved_grads: Vec<f64> = params.iter().map(|p| p.borrow().grad).collect();
+    params.iter().for_each(|p| p.borrow_mut().grad = 0.0);
+
+    loss().backward();
+    let analytic: Vec<f64> = params.iter().map(|p| p.borrow().grad).collect();
+
+    let eval = || {
+        let out = no_grad(&loss);
+        let data = out.borrow().data;
+        data
+    };
+
+    let errors = params
+        .iter()
+        .enumerate()
+        .map(|(i, param)| {
+            let original = param.borrow().data;
+            param.borrow_mut().data = original + eps;
+            let plus = eval();
+            param.borrow_mut().data = original - eps;
+            let minus = eval();
+            param.borrow_mut().data = original;
+
+            GradError::new(i, analytic[i], (plus - minus) / (2.0 * eps))
+        })
+        .collect();
+
+    for (param, grad) in params.iter().zip(saved_grads) {
+        param.borrow_mut().grad = grad;
+    }
+
+    GradCheckReport { errors }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::neural::MLP;
+    use crate::op::Op;
+
+    const EPS: f64 = 1e-6;
+
+    #[test]
+    fn builtin_ops() {
+        let report = gradcheck(
+            |x| {
+                let a = &x[0];
+                let b = &x[1];
+                let c = &x[2];
+                (a * b + c).tanh() / (a - c).powi(2)
+                    + a.pow(b.clone())
+                    + b.sigmoid() * c.exp()
+                    + (a * c).sin().abs()
+                    + b.softplus().ln()
+                    + c.gelu()
+                    + a.silu().sqrt()
+            },
+            &[1.5, 0.7, -0.4],
+            EPS,
+        );
+
+        assert_eq!(report.errors.len(), 3);
+        assert!(report.passes(1e-6, 1e-6), "{:?}", report.worst());
+    }
+
+    #[test]
+    fn detects_wrong_backward() {
+        // claims d/dx x^2 = x
+        struct BadSquare;
+        impl Op for BadSquare {
+            fn name(&self) -> String {
+                "bad_square".to_string()
+            }
+            fn forward(&self, inputs: &[f64]) -> f64 {
+                inputs[0] * inputs[0]
+            }
+            fn backward(&self, inputs: &[f64], _output: f64, grad: f64) -> Vec<f64> {
+                vec![grad * inputs[0]]
+            }
+        }
+
+        let report = gradcheck(|x| Value::apply(BadSquare, x), &[3.0], EPS);
+
+        let worst = report.worst().unwrap();
+        assert_eq!(worst.analytic, 3.0);
+        assert!((worst.numeric - 6.0).abs() < 1e-6);
+        assert!(!report.passes(1e-6, 1e-6));
+    }
+
+    #[test]
+    fn model_parameters() {
+        let mlp = MLP::new(3, vec![4, 4, 1]);
+        let params = mlp.parameters();
+        params[0].borrow_mut().grad = 42.0;
+
+        let report = gradcheck_params(
+            &params,
+            || {
+                let x = vec![Value::from(2.0), Value::from(3.0), Value::from(-1.0)];
+                let pred = mlp.forward(x)[0].tanh();
+                (pred - 1.0).powi(2)
+            },
+            EPS,
+        );
+
+        assert_eq!(report.errors.len(), params.len());
+        assert!(report.passes(1e-6, 1e-6), "{:?}", report.worst());
+        // the grads that were there before are left untouched
+        assert_eq!(params[0].borrow().grad, 42.0);
+    }
+}
@@ -1,6 +1,7 @@
 // this file is a different rendering implemenation than graph.rs.
 // This wil will use the graphviz-rust lib instead.
 
+use crate::id;
 use crate::neural::SubgraphTreeNode;
 use crate::Value;
 use graphviz_rust::dot_generator::*;
@@ -12,50 +13,45 @@ use graphviz_rust::{
     printer::{DotPrinter, PrinterContext},
 };
 use std::collections::HashMap;
-use std::collections::HashSet;
 use std::vec;
-use uuid::Uuid;
 
-fn get_prevs_of_recursive(v: &Value, set: &mut HashSet<Value>) -> Vec<Value> {
-    let mut values = vec![];
-    for prev in v.borrow().prev.iter() {
-        if set.contains(prev) {
-            continue;
-        }
-        set.insert(prev.clone());
-        values.push(prev.clone());
-        values.extend(get_prevs_of_recursive(prev, set));
-    }
-    values
+// Node ids come from the values' ids, so a graph built after `id::seed_ids` renders
+// to the same text every time.
+fn value_node_id(v: &Value) -> String {
+    format!("value{}", v.borrow().id)
 }
 
-fn get_all_values(v: &Value) -> Vec<Value> {
-    let mut set = HashSet::new();
-    let mut all_values = get_prevs_of_recursive(v, &mut set);
-    all_values.push(v.clone());
-    all_values
+/// The id of the oval showing the op that produced `v`.
+fn op_node_id(v: &Value) -> String {
+    format!("op{}", v.borrow().id)
 }
 
-fn value_to_statements(
-    v: &Value,
-    values_corresponding_op_node: &mut HashMap<Uuid, u128>,
-) -> Vec<Stmt> {
+/// Quotes `text` for use as a DOT label, escaping the characters that would end or
+/// break the quoted string, as labels and op names may contain them.
+fn quoted(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn value_to_statements(v: &Value) -> Vec<Stmt> {
     let mut statements = vec![];
 
-    let id = &v.borrow().uuid.as_u128();
-    let label = format!(
-        "\"data={:.4} grad={:.4}\"",
-        v.borrow().data,
-        v.borrow().grad,
-    );
+    let id = value_node_id(v);
+    let data = format!("data={:.4} grad={:.4}", v.borrow().data, v.borrow().grad);
+    let label = match &v.borrow().label {
+        Some(name) => quoted(&format!("{} | {}", name, data)),
+        None => quoted(&data),
+    };
     let node = stmt!(node!(id; NodeAttributes::shape(shape::box_),  NodeAttributes::label(label)));
     statements.push(node);
 
     if let Some(op) = &v.borrow().op {
-        let label_node_id = Uuid::new_v4().as_u128();
-        values_corresponding_op_node.insert(v.borrow().uuid, label_node_id);
+        let op = match v.borrow().constant {
+            Some(constant) => format!("{} {}", op, constant),
+            None => op.clone(),
+        };
+        let label_node_id = op_node_id(v);
         let label_node = stmt!(
-            node!(label_node_id; NodeAttributes::shape(shape::oval),  NodeAttributes::label("\"".to_string() + op + "\""))
+            node!(label_node_id; NodeAttributes::shape(shape::oval),  NodeAttributes::label(quoted(&op)))
         );
         statements.push(label_node);
 
@@ -69,18 +65,14 @@ fn value_to_statements(
 /// recursively render the subgraph tree node and all it's children
 fn render_subgraph_tree_recursive(
     subgraph: &SubgraphTreeNode,
-    subgraph_to_value_map: &HashMap<Uuid, Vec<Value>>,
-    values_corresponding_op_node: &mut HashMap<Uuid, u128>,
+    subgraph_to_value_map: &HashMap<id::Id, Vec<Value>>,
 ) -> Vec<Stmt> {
     // recursively turn the child subgraphs into statements
     let mut childrens_statments = vec![];
 
     for child_subgraph in subgraph.children.iter() {
-        let this_child_statements = render_subgraph_tree_recursive(
-            child_subgraph,
-            subgraph_to_value_map,
-            values_corresponding_op_node,
-        );
+        let this_child_statements =
+            render_subgraph_tree_recursive(child_subgraph, subgraph_to_value_map);
         childrens_statments.extend(this_child_statements)
     }
 
@@ -95,13 +87,12 @@ fn render_subgraph_tree_recursive(
 
         // add all the nodes to the subgraph
         for value in this_subgraphs_values.iter() {
-            this_subgraph_statements
-                .extend(value_to_statements(value, values_corresponding_op_node));
+            this_subgraph_statements.extend(value_to_statements(value));
         }
 
         // add attributes to the subgraph
         let attributes = vec![
-            SubgraphAttributes::label("\"".to_string() + &subgraph.label + "\""),
+            SubgraphAttributes::label(quoted(&subgraph.label)),
             SubgraphAttributes::color(color_name::blue),
             // SubgraphAttributes::margin(100.0),
             // same,min,source,max,sink
@@ -114,8 +105,7 @@ fn render_subgraph_tree_recursive(
         // append children to this subgraph
         this_subgraph_statements.extend(childrens_statments);
 
-        let this_subgraph_id =
-            "cluster".to_owned() + subgraph.subgraph_id.as_u128().to_string().as_str();
+        let this_subgraph_id = "cluster".to_owned() + subgraph.subgraph_id.to_string().as_str();
         stmt!(subgraph!(this_subgraph_id, this_subgraph_statements))
     };
 
@@ -125,20 +115,14 @@ fn render_subgraph_tree_recursive(
 fn create_graph(v: &Value, subgraph_tree: SubgraphTreeNode) -> Graph {
     // let graph = graph!(directed);
 
-    let mut values_corresponding_op_node: HashMap<Uuid, u128> = HashMap::new();
-
-    let values = get_all_values(v);
+    let values: Vec<Value> = v.nodes().collect();
 
-    let subgraph_map = get_subgraph_map(v);
+    let subgraph_map = v.subgraph_values();
 
     let mut graph_statements = vec![];
 
     // create all nodes in all subgraphs
-    let subgraphs = render_subgraph_tree_recursive(
-        &subgraph_tree,
-        &subgraph_map,
-        &mut values_corresponding_op_node,
-    );
+    let subgraphs = render_subgraph_tree_recursive(&subgraph_tree, &subgraph_map);
 
     graph_statements.extend(subgraphs);
 
@@ -150,10 +134,7 @@ fn create_graph(v: &Value, subgraph_tree: SubgraphTreeNode) -> Graph {
                 continue;
             }
 
-            nodes_outside_subgraphs.extend(value_to_statements(
-                value,
-                &mut values_corresponding_op_node,
-            ));
+            nodes_outside_subgraphs.extend(value_to_statements(value));
         }
         nodes_outside_subgraphs
     };
@@ -162,11 +143,11 @@ fn create_graph(v: &Value, subgraph_tree: SubgraphTreeNode) -> Graph {
     // create all edges
     let mut edge_statements = vec![];
     for value in values.iter() {
-        let node_or_label_node_id = match values_corresponding_op_node.get(&value.borrow().uuid) {
-            Some(op_node_id) => *op_node_id,
-            None => value.borrow().uuid.as_u128(),
+        let node_or_label_node_id = match value.borrow().op {
+            Some(_) => op_node_id(value),
+            None => value_node_id(value),
         };
-        for prev in value.borrow().prev.iter() {
+        for prev in value.children() {
             let edge_attributes = vec![
                 // attr!("color", "black"),
                 // attr!("penwidth", 2.0),
@@ -174,7 +155,7 @@ fn create_graph(v: &Value, subgraph_tree: SubgraphTreeNode) -> Graph {
                 // EdgeAttributes::splines(splines::ortho),
             ];
             let edge = stmt!(
-                edge!(node_id!(prev.borrow().uuid.as_u128()) => node_id!(node_or_label_node_id),edge_attributes)
+                edge!(node_id!(value_node_id(&prev)) => node_id!(node_or_label_node_id),edge_attributes)
             );
             edge_statements.push(edge);
         }
@@ -199,25 +180,6 @@ fn create_graph(v: &Value, subgraph_tree: SubgraphTreeNode) -> Graph {
     graph
 }
 
-fn get_subgraph_map(v: &Value) -> HashMap<Uuid, Vec<Value>> {
-    let mut map: HashMap<Uuid, Vec<Value>> = HashMap::new();
-
-    let values = get_all_values(v);
-
-    for value in values.iter() {
-        if let Some(subgraph_id) = value.borrow().subgraph_id {
-            match map.get_mut(&subgraph_id) {
-                Some(vec) => vec.push(value.clone()),
-                None => {
-                    map.insert(subgraph_id, vec![value.clone()]);
-                }
-            }
-        }
-    }
-
-    map
-}
-
 pub fn render_graph(v: &Value, subgraph_tree: SubgraphTreeNode) -> Option<()> {
     let graph = create_graph(v, subgraph_tree);
 
@@ -239,3 +201,36 @@ pub fn render_graph(v: &Value, subgraph_tree: SubgraphTreeNode) -> Option<()> {
 
     Some(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render() -> String {
+        id::seed_ids(0);
+        let subgraph_id = id::Id::next();
+        let a = Value::from(2.0).named("a");
+        let b = Value::from(-3.0).named("say \"hi\" \\");
+        let c = &a * &b;
+        c.borrow_mut().subgraph_id = Some(subgraph_id);
+        let d = (&c + 1.0).tanh();
+        d.backward();
+
+        let tree = SubgraphTreeNode {
+            subgraph_id,
+            label: "Neuron".to_string(),
+            children: vec![],
+        };
+        create_graph(&d, tree).print(&mut PrinterContext::default())
+    }
+
+    #[test]
+    fn deterministic_dot() {
+        let text = render();
+        assert_eq!(render(), text);
+        assert!(text.contains("cluster0"));
+        assert!(graphviz_rust::parse(&text).is_ok());
+        assert!(text.contains("value1 -> op3"));
+        assert!(text.contains("\"a | data=2.0000 grad="));
+    }
+}
@@ -0,0 +1,71 @@
+// Cheap ids for values and subgraphs, handed out by a per thread counter. Ids only
+// name things, e.g. the nodes of a rendered graph; `Value`s are compared by address.
+
+use std::cell::Cell;
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+thread_local! {
+    static NEXT_ID: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Identifies a value or a subgraph, such as a `Neuron`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Id(pub u64);
+
+impl Id {
+    /// The next id of this thread's counter.
+    pub fn next() -> Id {
+        NEXT_ID.with(|next| Id(next.replace(next.get() + 1)))
+    }
+
+    /// Makes sure this thread's counter won't hand out `self` later, for ids that
+    /// were restored rather than handed out, e.g. when loading a graph from JSON.
+    pub(crate) fn reserve(self) {
+        NEXT_ID.with(|next| next.set(next.get().max(self.0 + 1)));
+    }
+}
+
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Makes the ids handed out on this thread from now on start at `seed`. Seeding
+/// before building a graph gives its values and subgraphs the same ids every run, so
+/// e.g. its rendered DOT text is byte for byte the same.
+///
+/// Ids handed out before seeding may be handed out again, which only matters if
+/// graphs built before and after are rendered or saved together.
+#[allow(dead_code)]
+pub fn seed_ids(seed: u64) {
+    NEXT_ID.with(|next| next.set(seed));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+
+    #[test]
+    fn seeded() {
+        seed_ids(10);
+        assert_eq!(Id::next(), Id(10));
+        assert_eq!(Id::next(), Id(11));
+
+        seed_ids(10);
+        let a = Value::from(1.0);
+        let b = Value::from(1.0);
+        assert_eq!(a.borrow().id, Id(10));
+        assert_eq!(b.borrow().id, Id(11));
+
+        // the same ids don't make the same value
+        seed_ids(10);
+        let c = Value::from(1.0);
+        assert_eq!(c.borrow().id, a.borrow().id);
+        assert_ne!(c, a);
+    }
+}
@@ -0,0 +1,169 @@
+// Read only queries about the graph ending at a value, e.g. how many values one
+// `MLP::forward` creates or which op they mostly come from.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::float::Float;
+use crate::id::Id;
+use crate::value::Value;
+
+/// A summary of the graph ending at a value. See `Value::stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphStats {
+    /// The number of values in the graph, including the leaves.
+    pub values: usize,
+    pub leaves: usize,
+    /// The leaves that belong to a subgraph, i.e. the weights and biases of the
+    /// neurons the graph was built through.
+    pub parameters: usize,
+    /// The longest chain of ops from a leaf to the root.
+    pub depth: usize,
+    /// The number of values produced by each op.
+    pub ops: HashMap<String, usize>,
+    /// The number of values in each subgraph, such as a `Neuron` or `Layer`. Values
+    /// that aren't in a subgraph aren't counted.
+    pub subgraphs: HashMap<Id, usize>,
+}
+
+impl<T: Float> Value<T> {
+    /// Iterates over `self` and every value it was computed from, each after all of
+    /// its inputs, so `self` comes last.
+    pub fn nodes(&self) -> std::vec::IntoIter<Value<T>> {
+        self.build_topo().into_iter()
+    }
+
+    /// The values `self` was computed from, i.e. the inputs of its op in order.
+    pub fn children(&self) -> Vec<Value<T>> {
+        self.borrow().prev.clone()
+    }
+
+    /// The values in the graph ending at `root` that take `self` as an input. Values
+    /// only point at their inputs, so this walks all of `root`'s graph.
+    pub fn parents(&self, root: &Value<T>) -> Vec<Value<T>> {
+        root.nodes()
+            .filter(|value| value.borrow().prev.contains(self))
+            .collect()
+    }
+
+    /// The longest chain of ops from a leaf to `self`. Leaves have a depth of 0.
+    pub fn depth(&self) -> usize {
+        let mut depths: HashMap<Value<T>, usize> = HashMap::new();
+        for value in self.nodes() {
+            let depth = value
+                .borrow()
+                .prev
+                .iter()
+                .map(|prev| depths[prev] + 1)
+                .max()
+                .unwrap_or(0);
+            depths.insert(value, depth);
+        }
+        depths[self]
+    }
+
+    /// Counts the values, ops and subgraphs in the graph ending at `self`.
+    pub fn stats(&self) -> GraphStats {
+        let mut stats = GraphStats {
+            values: 0,
+            leaves: 0,
+            parameters: 0,
+            depth: self.depth(),
+            ops: HashMap::new(),
+            subgraphs: HashMap::new(),
+        };
+
+        for value in self.nodes() {
+            let v = value.borrow();
+            stats.values += 1;
+            if value.is_leaf() {
+                stats.leaves += 1;
+                if v.subgraph_id.is_some() {
+                    stats.parameters += 1;
+                }
+            }
+            if let Some(op) = &v.op {
+                *stats.ops.entry(op.clone()).or_insert(0) += 1;
+            }
+            if let Some(subgraph_id) = v.subgraph_id {
+                *stats.subgraphs.entry(subgraph_id).or_insert(0) += 1;
+            }
+        }
+
+        stats
+    }
+
+    /// The distinct subgraphs in the graph ending at `self`, mapped to their values
+    /// in topological order.
+    pub fn subgraph_values(&self) -> HashMap<Id, Vec<Value<T>>> {
+        let mut map: HashMap<Id, Vec<Value<T>>> = HashMap::new();
+        for value in self.nodes() {
+            let subgraph_id = value.borrow().subgraph_id;
+            if let Some(subgraph_id) = subgraph_id {
+                map.entry(subgraph_id).or_default().push(value);
+            }
+        }
+        map
+    }
+
+    /// The leaves of the graph ending at `self`, without duplicates.
+    pub fn leaves(&self) -> Vec<Value<T>> {
+        let mut seen = HashSet::new();
+        self.nodes()
+            .filter(|value| value.is_leaf() && seen.insert(value.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::neural::MLP;
+
+    #[test]
+    fn walk() {
+        let a = Value::from(2.0);
+        let b = Value::from(3.0);
+        let c = &a * &b;
+        let d = (&c + &a).tanh();
+
+        let nodes: Vec<Value> = d.nodes().collect();
+        assert_eq!(nodes.len(), 5);
+        assert_eq!(nodes.last(), Some(&d));
+        for (i, value) in nodes.iter().enumerate() {
+            for child in value.children() {
+                assert!(nodes[..i].contains(&child));
+            }
+        }
+
+        assert_eq!(c.children(), vec![a.clone(), b.clone()]);
+        assert_eq!(a.parents(&d).len(), 2);
+        assert_eq!(b.parents(&d), vec![c.clone()]);
+        assert!(d.parents(&d).is_empty());
+        assert_eq!(d.leaves(), vec![a.clone(), b.clone()]);
+
+        assert_eq!(a.depth(), 0);
+        assert_eq!(c.depth(), 1);
+        assert_eq!(d.depth(), 3);
+    }
+
+    #[test]
+    fn mlp_stats() {
+        let mlp = MLP::new(3, vec![4, 1]);
+        let x = vec![Value::from(2.0), Value::from(3.0), Value::from(-1.0)];
+        let out = mlp.forward(x)[0].clone();
+
+        let stats = out.stats();
+
+        // each neuron multiplies its inputs by its weights, sums them and adds its bias
+        assert_eq!(stats.ops["*"], 4 * 3 + 4);
+        assert_eq!(stats.ops["+"], 2 * 5);
+        assert_eq!(stats.parameters, mlp.parameters().len());
+        assert_eq!(stats.leaves, mlp.parameters().len() + 3);
+        assert_eq!(stats.values, stats.leaves + 16 + 10);
+        assert_eq!(stats.depth, 6);
+        // one subgraph per neuron, each holding its parameters and its ops
+        assert_eq!(stats.subgraphs.len(), 5);
+        assert_eq!(stats.subgraphs.values().sum::<usize>(), stats.values - 3);
+        assert_eq!(out.subgraph_values().len(), 5);
+    }
+}
@@ -0,0 +1,418 @@
+// Saves a whole graph to JSON and loads it back, e.g. to inspect or re-render a
+// training run's loss graph offline.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::float::Float;
+use crate::id::Id;
+use crate::value::{enable_grad, Value, ValueData};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Serialize, Deserialize)]
+struct GraphJson {
+    root: Id,
+    /// Every value in the graph, each after all of its inputs.
+    nodes: Vec<NodeJson>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct NodeJson {
+    id: Id,
+    #[serde(with = "number")]
+    data: f64,
+    #[serde(with = "number")]
+    grad: f64,
+    op: Option<String>,
+    /// Set if `op` is a user defined op, which can't be rebuilt even if its name is
+    /// that of a built in op.
+    #[serde(default)]
+    custom: bool,
+    #[serde(with = "optional_number")]
+    constant: Option<f64>,
+    subgraph_id: Option<Id>,
+    #[serde(default)]
+    label: Option<String>,
+    /// Set for values whose inputs were dropped by `backward_and_free`, which are
+    /// loaded without inputs too.
+    #[serde(default)]
+    freed: bool,
+    prev: Vec<Id>,
+}
+
+/// A number as written to JSON, which has no NaN or infinity, so those are written
+/// as the strings `"NaN"`, `"inf"` and `"-inf"`.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Number {
+    Finite(f64),
+    NonFinite(String),
+}
+
+impl Number {
+    fn new(x: f64) -> Number {
+        match x {
+            x if x.is_finite() => Number::Finite(x),
+            x if x.is_nan() => Number::NonFinite("NaN".to_string()),
+            x if x > 0.0 => Number::NonFinite("inf".to_string()),
+            _ => Number::NonFinite("-inf".to_string()),
+        }
+    }
+
+    fn get<E: serde::de::Error>(self) -> Result<f64, E> {
+        match self {
+            Number::Finite(x) => Ok(x),
+            Number::NonFinite(text) => match text.as_str() {
+                "NaN" => Ok(f64::NAN),
+                "inf" => Ok(f64::INFINITY),
+                "-inf" => Ok(f64::NEG_INFINITY),
+                _ => Err(E::custom(format!("invalid number {:?}", text))),
+            },
+        }
+    }
+}
+
+mod number {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(x: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        Number::new(*x).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        // older files wrote every non-finite number as null
+        match Option::<Number>::deserialize(deserializer)? {
+            Some(number) => number.get(),
+            None => Ok(f64::NAN),
+        }
+    }
+}
+
+mod optional_number {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(x: &Option<f64>, serializer: S) -> Result<S::Ok, S::Error> {
+        x.map(Number::new).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<f64>, D::Error> {
+        Option::<Number>::deserialize(deserializer)?
+            .map(Number::get)
+            .transpose()
+    }
+}
+
+/// Returned by `Value::from_json` when the JSON doesn't describe a graph that can be
+/// rebuilt.
+#[derive(Debug)]
+pub enum JsonError {
+    /// The text isn't JSON in the format written by `Value::to_json`.
+    Parse(serde_json::Error),
+    /// A value's op isn't a built in op, or doesn't take the inputs it was given.
+    /// User defined ops can't be rebuilt from their name.
+    UnknownOp(String),
+    /// A value refers to an input, or the root refers to a value, that doesn't come
+    /// before it in the file.
+    MissingValue(Id),
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonError::Parse(err) => write!(f, "invalid graph JSON: {}", err),
+            JsonError::UnknownOp(op) => write!(f, "can't rebuild op {:?}", op),
+            JsonError::MissingValue(id) => write!(f, "value {} is missing", id),
+        }
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+impl From<serde_json::Error> for JsonError {
+    fn from(err: serde_json::Error) -> JsonError {
+        JsonError::Parse(err)
+    }
+}
+
+impl<T: Float> Value<T> {
+    /// Writes `self` and every value it was computed from as JSON, including each
+    /// value's `data`, `grad`, `op`, `id`, `subgraph_id`, `label`, inputs and whether
+    /// it was freed. Hooks, `retain_grad` and user defined ops aren't saved.
+    pub fn to_json(&self) -> String {
+        let nodes = self
+            .build_topo()
+            .iter()
+            .map(|value| {
+                let v = value.borrow();
+                NodeJson {
+                    id: v.id,
+                    data: v.data.to_f64(),
+                    grad: v.grad.to_f64(),
+                    op: v.op.clone(),
+                    custom: v.custom_op.is_some(),
+                    constant: v.constant.map(Float::to_f64),
+                    subgraph_id: v.subgraph_id,
+                    label: v.label.clone(),
+                    freed: v.freed,
+                    prev: v.prev.iter().map(|prev| prev.borrow().id).collect(),
+                }
+            })
+            .collect();
+
+        let graph = GraphJson {
+            root: self.borrow().id,
+            nodes,
+        };
+        serde_json::to_string_pretty(&graph).unwrap()
+    }
+
+    /// Rebuilds a graph written by `to_json`, returning its root. Each op is rebuilt
+    /// from its name, so `backward` can be run on the result. Values that were freed
+    /// by `backward_and_free` are loaded freed, so `backward` still fails on them.
+    ///
+    /// The loaded values keep the ids, subgraphs and labels they were saved with, so
+    /// saving them again gives back the same JSON. They are still new values, which
+    /// don't compare equal to the values they were saved from.
+    pub fn from_json(json: &str) -> Result<Value<T>, JsonError> {
+        let graph: GraphJson = serde_json::from_str(json)?;
+        enable_grad(|| Value::from_graph_json(graph))
+    }
+
+    fn from_graph_json(graph: GraphJson) -> Result<Value<T>, JsonError> {
+        let mut values: HashMap<Id, Value<T>> = HashMap::new();
+        for node in graph.nodes {
+            let prev = node
+                .prev
+                .iter()
+                .map(|id| values.get(id).cloned().ok_or(JsonError::MissingValue(*id)))
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let value = match &node.op {
+                // the op's inputs are gone, so it can't be rebuilt
+                Some(op) if node.freed => {
+                    let mut v = ValueData::new(T::ZERO);
+                    v.op = Some(op.clone());
+                    v.constant = node.constant.map(T::from_f64);
+                    v.freed = true;
+                    Value::new(v)
+                }
+                Some(op) if node.custom => return Err(JsonError::UnknownOp(op.clone())),
+                Some(op) => {
+                    let constant = node.constant.map(T::from_f64);
+                    Value::from_op(op, &prev, constant)
+                        .ok_or_else(|| JsonError::UnknownOp(op.clone()))?
+                }
+                None => Value::new(ValueData::new(T::ZERO)),
+            };
+
+            {
+                let mut v = value.borrow_mut();
+                v.data = T::from_f64(node.data);
+                v.grad = T::from_f64(node.grad);
+                v.id = node.id;
+                v.subgraph_id = node.subgraph_id;
+                v.label = node.label;
+            }
+            // values created after loading mustn't share the restored ids
+            node.id.reserve();
+            if let Some(subgraph_id) = node.subgraph_id {
+                subgraph_id.reserve();
+            }
+            values.insert(node.id, value);
+        }
+
+        values
+            .remove(&graph.root)
+            .ok_or(JsonError::MissingValue(graph.root))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::neural::MLP;
+
+    #[test]
+    fn round_trip() {
+        let a = Value::from(2.0).named("a");
+        let b = Value::from(-3.0);
+        let c = ((&a * &b + 1.0).tanh() - 2.0 / &a).powi(2) + a.pow(b.clone()).max(&b);
+        c.backward();
+
+        let loaded = Value::<f64>::from_json(&c.to_json()).unwrap();
+
+        let original = c.build_topo();
+        let reloaded = loaded.build_topo();
+        assert_eq!(original.len(), reloaded.len());
+        // each input is at the same position in both graphs
+        let position = |values: &[Value], value: &Value| values.iter().position(|v| v == value);
+        for (v, w) in original.iter().zip(reloaded.iter()) {
+            let (v, w) = (v.borrow(), w.borrow());
+            assert_eq!(v.data, w.data);
+            assert_eq!(v.grad, w.grad);
+            assert_eq!(v.op, w.op);
+            assert_eq!(v.constant, w.constant);
+            assert_eq!(v.id, w.id);
+            assert_eq!(v.label, w.label);
+            assert_eq!(v.prev.len(), w.prev.len());
+            for (p, q) in v.prev.iter().zip(w.prev.iter()) {
+                assert_eq!(position(&original, p), position(&reloaded, q));
+            }
+        }
+    }
+
+    #[test]
+    fn identical_after_reload() {
+        let mlp = MLP::new(2, vec![3, 1]);
+        let x = vec![Value::from(0.5), Value::from(-2.0)];
+        let loss = (mlp.forward(x)[0].tanh() - 1.0).powi(2);
+        loss.backward();
+
+        let json = loss.to_json();
+        assert_eq!(Value::<f64>::from_json(&json).unwrap().to_json(), json);
+    }
+
+    #[test]
+    fn freed_graph() {
+        let a = Value::from(2.0);
+        let b = (&a * 3.0).tanh() + 1.0;
+        b.backward_and_free().unwrap();
+
+        let json = b.to_json();
+        let loaded = Value::<f64>::from_json(&json).unwrap();
+
+        assert_eq!(loaded.to_json(), json);
+        assert_eq!(loaded.borrow().op.as_deref(), Some("+"));
+        assert_eq!(loaded.borrow().data, b.borrow().data);
+        assert!(!loaded.is_leaf());
+        assert!(loaded.backward_and_free().is_err());
+    }
+
+    #[test]
+    fn load_inside_no_grad() {
+        let a = Value::from(2.0);
+        let json = (&a * 3.0).tanh().to_json();
+
+        let loaded = crate::value::no_grad(|| Value::<f64>::from_json(&json).unwrap());
+        assert_eq!(loaded.to_json(), json);
+    }
+
+    #[test]
+    fn new_ids_after_loading() {
+        // e.g. a graph saved by a run that had created more values than this one
+        crate::id::seed_ids(100);
+        let a = Value::from(2.0);
+        let json = (&a * 3.0).to_json();
+
+        crate::id::seed_ids(0);
+        let loaded = Value::<f64>::from_json(&json).unwrap();
+        assert_eq!(loaded.borrow().id, Id(101));
+        assert_eq!((&loaded + 1.0).borrow().id, Id(102));
+    }
+
+    #[test]
+    fn backward_after_loading() {
+        let mlp = MLP::new(3, vec![4, 1]);
+        let x = vec![Value::from(2.0), Value::from(3.0), Value::from(-1.0)];
+        let loss = (mlp.forward(x)[0].tanh() - 1.0).powi(2);
+
+        let loaded = Value::<f64>::from_json(&loss.to_json()).unwrap();
+        loss.backward();
+        loaded.backward();
+
+        let values = loss.build_topo();
+        let loaded_values = loaded.build_topo();
+        for param in mlp.parameters() {
+            let i = values.iter().position(|v| *v == param).unwrap();
+            let loaded_param = &loaded_values[i];
+            assert_eq!(loaded_param.borrow().grad, param.borrow().grad);
+            assert_eq!(
+                loaded_param.borrow().subgraph_id,
+                param.borrow().subgraph_id
+            );
+        }
+    }
+
+    #[test]
+    fn nan_round_trip() {
+        let a = Value::from(-3.0);
+        let b = Value::from(2.0);
+        let c = a.pow(b.clone());
+        c.backward();
+
+        let loaded = Value::<f64>::from_json(&c.to_json()).unwrap();
+
+        assert!(loaded.borrow().prev[1].borrow().grad.is_nan());
+    }
+
+    #[test]
+    fn infinity_round_trip() {
+        // sqrt has an infinite slope at 0
+        let a = Value::from(0.0);
+        let b = a.sqrt().clamp(0.0, f64::INFINITY) + Value::from(f64::NEG_INFINITY);
+        b.backward();
+
+        let json = b.to_json();
+        assert!(json.contains("\"inf\"") && json.contains("\"-inf\""));
+        let loaded = Value::<f64>::from_json(&json).unwrap();
+
+        assert_eq!(loaded.to_json(), json);
+        assert_eq!(loaded.borrow().data, f64::NEG_INFINITY);
+        let clamped = loaded.borrow().prev[0].clone();
+        assert_eq!(clamped.borrow().constant, Some(f64::INFINITY));
+        let a = loaded.leaves()[0].clone();
+        assert_eq!(a.borrow().grad, f64::INFINITY);
+
+        assert!(Value::<f64>::from_json(&json.replace("\"-inf\"", "\"lots\"")).is_err());
+    }
+
+    #[test]
+    fn errors() {
+        struct Double;
+        impl crate::op::Op for Double {
+            fn name(&self) -> String {
+                "double".to_string()
+            }
+            fn forward(&self, inputs: &[f64]) -> f64 {
+                inputs[0] * 2.0
+            }
+            fn backward(&self, _inputs: &[f64], _output: f64, grad: f64) -> Vec<f64> {
+                vec![grad * 2.0]
+            }
+        }
+
+        let a = Value::from(1.0);
+        let json = Value::apply(Double, &[a]).to_json();
+
+        assert!(matches!(
+            Value::<f64>::from_json(&json),
+            Err(JsonError::UnknownOp(op)) if op == "double"
+        ));
+
+        // named like a built in op, but still not rebuilt as one
+        struct Tanh;
+        impl crate::op::Op for Tanh {
+            fn name(&self) -> String {
+                "tanh()".to_string()
+            }
+            fn forward(&self, inputs: &[f64]) -> f64 {
+                inputs[0].tanh() * 2.0
+            }
+            fn backward(&self, _inputs: &[f64], output: f64, grad: f64) -> Vec<f64> {
+                vec![grad * (1.0 - output * output / 4.0) * 2.0]
+            }
+        }
+        let json = Value::apply(Tanh, &[Value::from(1.0)]).to_json();
+        assert!(matches!(
+            Value::<f64>::from_json(&json),
+            Err(JsonError::UnknownOp(op)) if op == "tanh()"
+        ));
+
+        assert!(matches!(
+            Value::<f64>::from_json("{}"),
+            Err(JsonError::Parse(_))
+        ));
+    }
+}
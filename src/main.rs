@@ -1,10 +1,30 @@
+// `Value`s are hashed by their address, which never changes.
+#![allow(clippy::mutable_key_type)]
+#![allow(clippy::suspicious_arithmetic_impl)]
+#![allow(clippy::upper_case_acronyms)]
+
+// Public API that `main` doesn't use is marked `#[allow(dead_code)]` item by item.
+mod anomaly;
+mod compile;
+mod dual;
+mod float;
+mod functional;
+mod gradcheck;
 mod graph;
+mod id;
+mod inspect;
+mod json;
 mod neural;
+mod op;
+mod optimize;
+mod parallel;
+mod rule;
+mod tape;
 mod value;
 use crate::value::Value;
 
 fn main() {
-    let inputs = vec![
+    let inputs = [
         Value::from(1.0),
         Value::from(2.0),
         Value::from(3.0),
@@ -14,7 +34,7 @@ fn main() {
     let mlp = neural::MLP::new(inputs.len().try_into().unwrap(), vec![4, 4, 1]);
 
     // create dataset
-    let xs = vec![
+    let xs = [
         vec![Value::from(2.0), Value::from(3.0), Value::from(-1.0)],
         // vec![Value::from(3.0), Value::from(-1.0), Value::from(0.5)],
         // vec![Value::from(0.5), Value::from(1.0), Value::from(1.0)],
@@ -47,14 +67,14 @@ fn main() {
         let losses: Vec<Value> = preds
             .iter()
             .zip(ys.iter())
-            .map(|(pred, y)| (pred.clone() - y.clone()).pow(Value::from(2.0)))
+            .map(|(pred, y)| (pred - y).powi(2))
             .collect();
 
         let batch_loss = losses.into_iter().sum::<Value>();
 
         println!("training cycle: {} loss: {:?}", i, batch_loss.borrow().data);
 
-        batch_loss.backward();
+        batch_loss.backward_and_free().unwrap();
 
         // update all the parameters
         let params = mlp.parameters();
@@ -80,7 +100,7 @@ fn main() {
         let losses: Vec<Value> = preds
             .iter()
             .zip(ys.iter())
-            .map(|(pred, y)| (pred.clone() - y.clone()).pow(Value::from(2.0)))
+            .map(|(pred, y)| (pred - y).powi(2))
             .collect();
 
         println!("losses: {:?}", losses);
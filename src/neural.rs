@@ -1,37 +1,84 @@
-use uuid::Uuid;
+use std::iter::Sum;
+use std::ops::{Add, Mul};
 
+use crate::float::Float;
+use crate::id::Id;
 use crate::Value;
 
+/// A number that `Neuron`, `Layer` and `MLP` can be run on, letting the same model
+/// run on different autograd backends such as `Value` or `tape::Var`.
+///
+/// Running a model also needs `&S * &S`, which can't be a bound here as it wouldn't
+/// be implied by `S: Scalar`, so it is a bound on the `forward` methods instead.
+pub trait Scalar:
+    Clone + Add<Output = Self> + for<'a> Add<&'a Self, Output = Self> + Mul<Output = Self> + Sum<Self>
+{
+    fn data(&self) -> f64;
+
+    /// Marks the scalar as belonging to a subgraph, for backends that can render
+    /// their graphs. Does nothing by default.
+    fn set_subgraph_id(&self, _subgraph_id: Option<Id>) {}
+
+    /// Names the scalar, e.g. `w0` for a neuron's first weight, for backends that
+    /// can label their values. Does nothing by default.
+    fn set_label(&self, _label: &str) {}
+}
+
+impl<T: Float> Scalar for Value<T> {
+    fn data(&self) -> f64 {
+        self.borrow().data.to_f64()
+    }
+
+    fn set_subgraph_id(&self, subgraph_id: Option<Id>) {
+        self.borrow_mut().subgraph_id = subgraph_id;
+    }
+
+    fn set_label(&self, label: &str) {
+        self.borrow_mut().label = Some(label.to_string());
+    }
+}
+
 pub struct SubgraphTreeNode {
-    pub subgraph_id: Uuid,
+    pub subgraph_id: Id,
     pub label: String,
     pub children: Vec<SubgraphTreeNode>,
 }
 
-pub struct Neuron {
-    weights: Vec<Value>,
-    bias: Value,
-    pub subgraph_id: Option<Uuid>,
+pub struct Neuron<S = Value> {
+    weights: Vec<S>,
+    bias: S,
+    pub subgraph_id: Option<Id>,
 }
 
 impl Neuron {
+    #[allow(dead_code)]
     pub fn new(nin: u32) -> Self {
-        let neuron_subgraph_id = Some(Uuid::new_v4());
+        Neuron::with_init(nin, &mut Value::from)
+    }
+}
+
+impl<S: Scalar> Neuron<S> {
+    /// Creates a neuron whose parameters are made by passing random values between -1
+    /// and 1 to `init`, e.g. to create them on a `tape::Tape`.
+    pub fn with_init(nin: u32, init: &mut impl FnMut(f64) -> S) -> Self {
+        let neuron_subgraph_id = Some(Id::next());
 
         // Initialize weights with random values between -1 and 1
-        let weights: Vec<Value> = (0..nin)
-            .map(|_| Value::from(rand::random::<f64>() * 2.0 - 1.0))
+        let weights: Vec<S> = (0..nin)
+            .map(|_| init(rand::random::<f64>() * 2.0 - 1.0))
             .collect();
 
-        // assign neuron's subgraph to each weight
-        for weight in weights.iter() {
-            weight.borrow_mut().subgraph_id = neuron_subgraph_id;
+        // assign neuron's subgraph to each weight, and name them w0, w1, ...
+        for (i, weight) in weights.iter().enumerate() {
+            weight.set_subgraph_id(neuron_subgraph_id);
+            weight.set_label(&format!("w{}", i));
         }
 
         // Initialize bias with random value between -1 and 1
-        let bias = Value::from(rand::random::<f64>() * 2.0 - 1.0);
+        let bias = init(rand::random::<f64>() * 2.0 - 1.0);
         // assign neuron's subgraph to bias
-        bias.borrow_mut().subgraph_id = neuron_subgraph_id;
+        bias.set_subgraph_id(neuron_subgraph_id);
+        bias.set_label("b");
 
         Neuron {
             weights,
@@ -40,26 +87,29 @@ impl Neuron {
         }
     }
 
-    pub fn forward(&self, inputs: Vec<Value>) -> Value {
-        let sum: Value = self
+    pub fn forward(&self, inputs: Vec<S>) -> S
+    where
+        for<'a> &'a S: Mul<&'a S, Output = S>,
+    {
+        let sum: S = self
             .weights
             .iter()
             .zip(inputs.iter())
             .map(|(weight, input)| {
-                let weighed_input = weight.clone() * input.clone();
-                weighed_input.borrow_mut().subgraph_id = self.subgraph_id;
+                let weighed_input = weight * input;
+                weighed_input.set_subgraph_id(self.subgraph_id);
                 weighed_input
             })
             .sum();
-        sum.borrow_mut().subgraph_id = self.subgraph_id;
+        sum.set_subgraph_id(self.subgraph_id);
 
-        let sum_plus_bias = sum + self.bias.clone();
-        sum_plus_bias.borrow_mut().subgraph_id = self.subgraph_id;
+        let sum_plus_bias = sum + &self.bias;
+        sum_plus_bias.set_subgraph_id(self.subgraph_id);
 
         sum_plus_bias
     }
 
-    pub fn parameters(&self) -> Vec<Value> {
+    pub fn parameters(&self) -> Vec<S> {
         let mut params = self.weights.clone();
         params.push(self.bias.clone());
 
@@ -78,16 +128,25 @@ impl Neuron {
     }
 }
 
-pub struct Layer {
-    neurons: Vec<Neuron>,
-    subgraph_id: Option<Uuid>,
+pub struct Layer<S = Value> {
+    neurons: Vec<Neuron<S>>,
+    subgraph_id: Option<Id>,
 }
 
 impl Layer {
+    #[allow(dead_code)]
     pub fn new(nin: u32, nout: u32) -> Self {
-        let layer_subgraph_id = Some(Uuid::new_v4());
+        Layer::with_init(nin, nout, &mut Value::from)
+    }
+}
 
-        let neurons = (0..nout).map(|_| Neuron::new(nin)).collect();
+impl<S: Scalar> Layer<S> {
+    /// Creates a layer whose parameters are made by passing random values between -1
+    /// and 1 to `init`.
+    pub fn with_init(nin: u32, nout: u32, init: &mut impl FnMut(f64) -> S) -> Self {
+        let layer_subgraph_id = Some(Id::next());
+
+        let neurons = (0..nout).map(|_| Neuron::with_init(nin, init)).collect();
 
         Layer {
             neurons,
@@ -95,14 +154,17 @@ impl Layer {
         }
     }
 
-    pub fn forward(&self, inputs: Vec<Value>) -> Vec<Value> {
+    pub fn forward(&self, inputs: Vec<S>) -> Vec<S>
+    where
+        for<'a> &'a S: Mul<&'a S, Output = S>,
+    {
         self.neurons
             .iter()
             .map(|neuron| neuron.forward(inputs.clone()))
             .collect()
     }
 
-    pub fn parameters(&self) -> Vec<Value> {
+    pub fn parameters(&self) -> Vec<S> {
         self.neurons
             .iter()
             .flat_map(|neuron| neuron.parameters())
@@ -125,30 +187,55 @@ impl Layer {
     }
 }
 
-pub struct MLP {
-    layers: Vec<Layer>,
-    subgraph_id: Option<Uuid>,
+pub struct MLP<S = Value> {
+    nin: u32,
+    nouts: Vec<u32>,
+    layers: Vec<Layer<S>>,
+    subgraph_id: Option<Id>,
 }
 
 impl MLP {
     pub fn new(nin: u32, nouts: Vec<u32>) -> Self {
-        let mlp_subgraph_id = Some(Uuid::new_v4());
+        MLP::with_init(nin, nouts, Value::from)
+    }
+}
+
+impl<T: Float> MLP<Value<T>> {
+    pub fn zero_grad(&self) {
+        let params = self.parameters();
+        for param in params.iter() {
+            param.borrow_mut().grad = T::ZERO;
+        }
+    }
+}
+
+impl<S: Scalar> MLP<S> {
+    /// Creates an MLP whose parameters are made by passing random values between -1
+    /// and 1 to `init`, e.g. `|x| tape.var(x)` to run it on a `tape::Tape` or
+    /// `|x| Value::from(x as f32)` to run it in single precision.
+    pub fn with_init(nin: u32, nouts: Vec<u32>, mut init: impl FnMut(f64) -> S) -> Self {
+        let mlp_subgraph_id = Some(Id::next());
 
         let mut layers = Vec::new();
 
         let mut prev_nout = nin;
-        for nout in nouts {
-            layers.push(Layer::new(prev_nout, nout));
+        for &nout in nouts.iter() {
+            layers.push(Layer::with_init(prev_nout, nout, &mut init));
             prev_nout = nout;
         }
 
         MLP {
+            nin,
+            nouts,
             layers,
             subgraph_id: mlp_subgraph_id,
         }
     }
 
-    pub fn forward(&self, inputs: Vec<Value>) -> Vec<Value> {
+    pub fn forward(&self, inputs: Vec<S>) -> Vec<S>
+    where
+        for<'a> &'a S: Mul<&'a S, Output = S>,
+    {
         let mut outputs = inputs;
         for layer in self.layers.iter() {
             outputs = layer.forward(outputs);
@@ -157,17 +244,19 @@ impl MLP {
         outputs
     }
 
-    pub fn parameters(&self) -> Vec<Value> {
+    pub fn parameters(&self) -> Vec<S> {
         self.layers
             .iter()
             .flat_map(|layer| layer.parameters())
             .collect()
     }
 
-    pub fn zero_grad(&self) {
-        let params = self.parameters();
-        for param in params.iter() {
-            param.borrow_mut().grad = 0.0;
+    #[allow(dead_code)]
+    pub fn snapshot(&self) -> MLPSnapshot {
+        MLPSnapshot {
+            nin: self.nin,
+            nouts: self.nouts.clone(),
+            parameters: self.parameters().iter().map(|param| param.data()).collect(),
         }
     }
 
@@ -186,3 +275,41 @@ impl MLP {
         None
     }
 }
+
+/// An MLP's shape and parameter data. Unlike the MLP itself this can be sent to
+/// other threads, e.g. to give each worker thread its own copy of the model.
+#[derive(Clone, Debug)]
+pub struct MLPSnapshot {
+    pub nin: u32,
+    pub nouts: Vec<u32>,
+    pub parameters: Vec<f64>,
+}
+
+#[allow(dead_code)]
+impl MLPSnapshot {
+    /// Builds an MLP with the snapshot's parameters, creating each one with `init`.
+    pub fn build<S: Scalar>(&self, mut init: impl FnMut(f64) -> S) -> MLP<S> {
+        // `MLP::with_init` creates parameters in the same order as `parameters` returns them
+        let mut parameters = self.parameters.iter();
+        MLP::with_init(self.nin, self.nouts.clone(), |_| {
+            init(*parameters.next().unwrap())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn neuron_labels() {
+        let labels: Vec<Option<String>> = Neuron::new(2)
+            .parameters()
+            .iter()
+            .map(|p| p.borrow().label.clone())
+            .collect();
+
+        let expected = ["w0", "w1", "b"].map(|label| Some(label.to_string()));
+        assert_eq!(labels, expected);
+    }
+}
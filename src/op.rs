@@ -0,0 +1,181 @@
+use std::fmt::{self, Debug};
+use std::rc::Rc;
+
+use crate::float::Float;
+use crate::value::{Value, ValueData};
+
+/// A user defined differentiable operation, for ops that need to carry state such
+/// as configurable bounds or a lookup table. Use `Value::apply` to add it to a graph.
+pub trait Op<T: Float = f64> {
+    /// The label shown for the op when rendering the graph.
+    fn name(&self) -> String;
+
+    /// Computes the op's output from the data of its inputs.
+    fn forward(&self, inputs: &[T]) -> T;
+
+    /// Given the data of the inputs, the op's output and the gradient flowing into
+    /// the output, returns the gradient for each input. Must return exactly one
+    /// gradient per input.
+    fn backward(&self, inputs: &[T], output: T, grad: T) -> Vec<T>;
+}
+
+impl<T: Float> Debug for dyn Op<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl<T: Float> Value<T> {
+    /// Applies a user defined `op` to `inputs`, producing a value that takes part in
+    /// `backward` like any built in op.
+    ///
+    /// `grad_graph` treats the local derivatives returned by `Op::backward` as
+    /// constants, so first order gradients through the op are exact but higher order
+    /// ones ignore the op's curvature.
+    pub fn apply(op: impl Op<T> + 'static, inputs: &[Value<T>]) -> Value<T> {
+        Value::apply_rc(Rc::new(op), inputs)
+    }
+
+    /// Like `apply`, for an op that is already shared with other values.
+    pub(crate) fn apply_rc(op: Rc<dyn Op<T>>, inputs: &[Value<T>]) -> Value<T> {
+        let data: Vec<T> = inputs.iter().map(|input| input.borrow().data).collect();
+        let mut new_value = ValueData::new(op.forward(&data));
+
+        new_value.prev = inputs.to_vec();
+        new_value.op = Some(op.name());
+        new_value.custom_op = Some(op);
+        new_value.backward = Some(|value: &ValueData<T>| {
+            let grads = input_grads(value, value.grad);
+            for (input, grad) in value.prev.iter().zip(grads) {
+                input.borrow_mut().grad += grad;
+            }
+        });
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            input_grads(&value.borrow(), T::ONE)
+                .into_iter()
+                .map(|local_grad| grad * local_grad)
+                .collect()
+        });
+
+        Value::new(new_value)
+    }
+}
+
+/// Runs the custom op's backward rule for `value`, reading all inputs before any of
+/// their grads are written, as the same value may be passed more than once.
+fn input_grads<T: Float>(value: &ValueData<T>, grad: T) -> Vec<T> {
+    let inputs: Vec<T> = value.prev.iter().map(|input| input.borrow().data).collect();
+    let op = value.custom_op.as_ref().unwrap();
+    checked_backward(op.as_ref(), &inputs, value.data, grad)
+}
+
+/// Runs `op.backward`, panicking if it doesn't return one gradient per input.
+pub(crate) fn checked_backward<T: Float>(
+    op: &dyn Op<T>,
+    inputs: &[T],
+    output: T,
+    grad: T,
+) -> Vec<T> {
+    let grads = op.backward(inputs, output, grad);
+    assert_eq!(
+        grads.len(),
+        inputs.len(),
+        "backward of op {} returned {} gradients for {} inputs",
+        op.name(),
+        grads.len(),
+        inputs.len()
+    );
+    grads
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Clamp {
+        lo: f64,
+        hi: f64,
+    }
+
+    impl Op for Clamp {
+        fn name(&self) -> String {
+            format!("clamp({}, {})", self.lo, self.hi)
+        }
+
+        fn forward(&self, inputs: &[f64]) -> f64 {
+            inputs[0].clamp(self.lo, self.hi)
+        }
+
+        fn backward(&self, inputs: &[f64], _output: f64, grad: f64) -> Vec<f64> {
+            if inputs[0] > self.lo && inputs[0] < self.hi {
+                vec![grad]
+            } else {
+                vec![0.0]
+            }
+        }
+    }
+
+    struct WeightedSum(Vec<f64>);
+
+    impl Op for WeightedSum {
+        fn name(&self) -> String {
+            "weighted_sum".to_string()
+        }
+
+        fn forward(&self, inputs: &[f64]) -> f64 {
+            inputs.iter().zip(self.0.iter()).map(|(x, w)| x * w).sum()
+        }
+
+        fn backward(&self, _inputs: &[f64], _output: f64, grad: f64) -> Vec<f64> {
+            self.0.iter().map(|w| w * grad).collect()
+        }
+    }
+
+    #[test]
+    fn apply() {
+        let a = Value::from(3.0);
+        let b = Value::apply(Clamp { lo: -1.0, hi: 1.0 }, std::slice::from_ref(&a));
+        let c = Value::apply(Clamp { lo: -5.0, hi: 5.0 }, std::slice::from_ref(&a));
+        let d = &b + &c * 2.0;
+
+        d.backward();
+
+        assert_eq!(b.borrow().data, 1.0);
+        assert_eq!(c.borrow().data, 3.0);
+        assert_eq!(b.borrow().op.as_deref(), Some("clamp(-1, 1)"));
+        assert_eq!(a.borrow().grad, 2.0);
+    }
+
+    #[test]
+    fn apply_same_input_twice() {
+        let a = Value::from(3.0);
+        let b = Value::apply(WeightedSum(vec![2.0, 5.0]), &[a.clone(), a.clone()]);
+
+        b.backward();
+
+        assert_eq!(b.borrow().data, 21.0);
+        assert_eq!(a.borrow().grad, 7.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "backward of op weighted_sum returned 1 gradients for 2 inputs")]
+    fn wrong_number_of_grads() {
+        let a = Value::from(3.0);
+        let b = Value::apply(WeightedSum(vec![2.0]), &[a.clone(), a.clone()]);
+
+        b.backward();
+    }
+
+    #[test]
+    fn apply_grad_graph() {
+        let a = Value::from(3.0);
+        let b = Value::from(4.0);
+        let c = Value::apply(WeightedSum(vec![2.0, 5.0]), &[a.clone(), b.clone()]).tanh();
+
+        let grads = c.grad_graph(&[a.clone(), b.clone()]);
+        c.backward();
+
+        assert_eq!(grads[0].borrow().data, a.borrow().grad);
+        assert_eq!(grads[1].borrow().data, b.borrow().grad);
+    }
+}
@@ -0,0 +1,412 @@
+// A graph rewrite pass that shrinks a graph without changing its value or the
+// gradients of the leaves it is differentiated with respect to. It folds ops whose
+// inputs are all constants, stores the remaining constant inputs on their ops,
+// removes identity ops such as `x * 1` and shares identical ops that were built more
+// than once.
+
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::float::Float;
+use crate::op::Op;
+use crate::value::{enable_grad, Value};
+
+/// What a value of the original graph became in the optimized one.
+#[derive(Clone)]
+enum Node<T: Float> {
+    Constant(T),
+    Value(Value<T>),
+}
+
+/// Identifies an op of the optimized graph by its op, constant and inputs.
+type OpKey<T> = (String, Option<u64>, Vec<Value<T>>);
+
+/// Returns a graph computing the same data as `root` whose backward pass gives the
+/// same gradients to each of the leaves in `wrt`, e.g. a model's parameters. Every
+/// other leaf is treated as a constant.
+///
+/// The leaves in `wrt` are shared with the original graph, and ops that don't
+/// change are reused from it. Ops with hooks or `retain_grad` are never removed or
+/// merged, and are rebuilt with their hooks, `retain_grad` and label.
+#[allow(dead_code)]
+pub fn optimize<T: Float>(root: &Value<T>, wrt: &[Value<T>]) -> Value<T> {
+    // the optimized graph is built through the ops, which only record their inputs
+    // while grad is enabled
+    enable_grad(|| optimize_graph(root, wrt))
+}
+
+fn optimize_graph<T: Float>(root: &Value<T>, wrt: &[Value<T>]) -> Value<T> {
+    let wrt: HashSet<&Value<T>> = wrt.iter().collect();
+    let mut nodes: HashMap<Value<T>, Node<T>> = HashMap::new();
+    let mut built: HashMap<OpKey<T>, Value<T>> = HashMap::new();
+
+    for value in root.nodes() {
+        let node = {
+            let v = value.borrow();
+            let inputs: Vec<Node<T>> = v.prev.iter().map(|prev| nodes[prev].clone()).collect();
+
+            if v.prev.is_empty() {
+                if wrt.contains(&value) {
+                    Node::Value(value.clone())
+                } else {
+                    Node::Constant(v.data)
+                }
+            } else if inputs
+                .iter()
+                .all(|input| matches!(input, Node::Constant(_)))
+            {
+                // the data was computed from the same constants
+                Node::Constant(v.data)
+            } else {
+                let op = v.op.clone().unwrap();
+                // values with hooks or a retained grad must keep their own place in the
+                // graph, and user defined ops can't be compared, so neither is shared
+                let observed = !v.hooks.is_empty() || v.retain_grad;
+                let builtin = v.custom_op.is_none();
+                let shareable = !observed && builtin;
+                // a user defined op may share its name with a built in one
+                let simplified = if observed || !builtin {
+                    None
+                } else {
+                    simplify(&op, &inputs, v.constant)
+                };
+                match simplified {
+                    Some(node) => node,
+                    None => {
+                        let unchanged = v.prev.iter().zip(inputs.iter()).all(
+                            |(prev, input)| matches!(input, Node::Value(input) if input == prev),
+                        );
+                        let (op, prev, constant) = if builtin {
+                            with_constants_inlined(&op, inputs, v.constant, observed)
+                        } else {
+                            (op, as_leaves(inputs), v.constant)
+                        };
+                        let key = (
+                            op.clone(),
+                            constant.map(|c| c.to_f64().to_bits()),
+                            prev.clone(),
+                        );
+
+                        match built.get(&key) {
+                            Some(existing) if shareable => Node::Value(existing.clone()),
+                            _ => {
+                                let new_value = if unchanged {
+                                    value.clone()
+                                } else {
+                                    rebuild(&op, &prev, constant, v.custom_op.clone(), &value)
+                                };
+                                if shareable {
+                                    built.insert(key, new_value.clone());
+                                }
+                                Node::Value(new_value)
+                            }
+                        }
+                    }
+                }
+            }
+        };
+        nodes.insert(value, node);
+    }
+
+    match nodes.remove(root).unwrap() {
+        Node::Constant(data) => Value::from(data),
+        Node::Value(value) => value,
+    }
+}
+
+/// Returns what an op reduces to if it is an identity, such as `x * 1` or `x + 0`.
+fn simplify<T: Float>(op: &str, inputs: &[Node<T>], constant: Option<T>) -> Option<Node<T>> {
+    let is = |c: T, x: f64| c == T::from_f64(x);
+    let node = match (op, inputs, constant) {
+        ("+" | "-", [x], Some(c)) if is(c, 0.0) => x.clone(),
+        ("*" | "/" | "powi()" | "powf()", [x], Some(c)) if is(c, 1.0) => x.clone(),
+        ("+", [x, Node::Constant(c)], None) | ("+", [Node::Constant(c), x], None)
+            if is(*c, 0.0) =>
+        {
+            x.clone()
+        }
+        ("-", [x, Node::Constant(c)], None) if is(*c, 0.0) => x.clone(),
+        ("*", [x, Node::Constant(c)], None) | ("*", [Node::Constant(c), x], None)
+            if is(*c, 1.0) =>
+        {
+            x.clone()
+        }
+        ("/" | "pow()", [x, Node::Constant(c)], None) if is(*c, 1.0) => x.clone(),
+        _ => return None,
+    };
+    Some(node)
+}
+
+/// Turns an op with constant inputs into the form of the op that stores the constant
+/// on the value, where there is one. The constants of other ops are given leaves of
+/// their own. The constants of `observed` sums aren't merged, as that could leave a
+/// single input and so remove the sum.
+fn with_constants_inlined<T: Float>(
+    op: &str,
+    inputs: Vec<Node<T>>,
+    constant: Option<T>,
+    observed: bool,
+) -> (String, Vec<Value<T>>, Option<T>) {
+    let inlined = match (op, inputs.as_slice(), constant) {
+        ("+", [Node::Value(a), Node::Constant(c)], None)
+        | ("+", [Node::Constant(c), Node::Value(a)], None) => Some(("+", a, *c)),
+        ("-", [Node::Value(a), Node::Constant(c)], None) => Some(("-", a, *c)),
+        ("-", [Node::Constant(c), Node::Value(a)], None) => Some(("rsub", a, *c)),
+        ("*", [Node::Value(a), Node::Constant(c)], None)
+        | ("*", [Node::Constant(c), Node::Value(a)], None) => Some(("*", a, *c)),
+        ("/", [Node::Value(a), Node::Constant(c)], None) => Some(("/", a, *c)),
+        ("/", [Node::Constant(c), Node::Value(a)], None) => Some(("rdiv", a, *c)),
+        ("pow()", [Node::Value(a), Node::Constant(c)], None) => Some(("powf()", a, *c)),
+        _ => None,
+    };
+    if let Some((op, a, c)) = inlined {
+        return (op.to_string(), vec![a.clone()], Some(c));
+    }
+
+    // sums of more than two values add up their constants into one
+    if op == "+" && inputs.len() > 2 && constant.is_none() && !observed {
+        let mut sum = T::ZERO;
+        let mut values = vec![];
+        for input in inputs {
+            match input {
+                Node::Constant(c) => sum += c,
+                Node::Value(value) => values.push(value),
+            }
+        }
+        if sum != T::ZERO {
+            values.push(Value::from(sum));
+        }
+        return (op.to_string(), values, None);
+    }
+
+    (op.to_string(), as_leaves(inputs), constant)
+}
+
+/// The inputs of an op, with constants given leaves of their own.
+fn as_leaves<T: Float>(inputs: Vec<Node<T>>) -> Vec<Value<T>> {
+    inputs
+        .into_iter()
+        .map(|input| match input {
+            Node::Constant(c) => Value::from(c),
+            Node::Value(value) => value,
+        })
+        .collect()
+}
+
+/// Builds a new op from its parts, copying over the parts of `original` that aren't
+/// part of the computation, such as its label.
+fn rebuild<T: Float>(
+    op: &str,
+    prev: &[Value<T>],
+    constant: Option<T>,
+    custom_op: Option<Rc<dyn Op<T>>>,
+    original: &Value<T>,
+) -> Value<T> {
+    let observed = {
+        let original = original.borrow();
+        !original.hooks.is_empty() || original.retain_grad
+    };
+    let value = match custom_op {
+        Some(custom_op) => Value::apply_rc(custom_op, prev),
+        None => match prev {
+            // a sum that was left with a single value
+            [value] if op == "+" && constant.is_none() && !observed => return value.clone(),
+            _ => Value::from_op(op, prev, constant).unwrap(),
+        },
+    };
+
+    {
+        let original = original.borrow();
+        let mut v = value.borrow_mut();
+        v.subgraph_id = original.subgraph_id;
+        v.hooks = original.hooks.clone();
+        v.retain_grad = original.retain_grad;
+        v.label = original.label.clone();
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::neural::MLP;
+
+    /// Checks that the optimized graph has the same data and gives `wrt` the same
+    /// gradients, and returns the number of values in it.
+    fn check(root: &Value, wrt: &[Value]) -> usize {
+        let optimized = optimize(root, wrt);
+        assert_eq!(optimized.borrow().data, root.borrow().data);
+
+        root.backward();
+        let grads: Vec<f64> = wrt.iter().map(|v| v.borrow().grad).collect();
+        // the optimized graph may share ops with the original
+        root.zero_grad();
+        optimized.backward();
+        for (v, grad) in wrt.iter().zip(grads) {
+            assert!((v.borrow().grad - grad).abs() < 1e-12);
+        }
+
+        optimized.nodes().count()
+    }
+
+    #[test]
+    fn folds_constants() {
+        let x = Value::from(3.0);
+        let two = Value::from(2.0);
+        let three = Value::from(3.0);
+        // (2 * 3).exp() folds to one constant, which is then stored on the `+`
+        let y = &x + (&two * &three).exp();
+        // the exponent becomes a constant of `powf`
+        let z = y.pow(two.clone()) - Value::from(1.0);
+
+        assert_eq!(z.nodes().count(), 9);
+        assert_eq!(check(&z, std::slice::from_ref(&x)), 4);
+
+        let optimized = optimize(&z, std::slice::from_ref(&x));
+        assert_eq!(optimized.borrow().op.as_deref(), Some("-"));
+        assert_eq!(optimized.borrow().constant, Some(1.0));
+        assert_eq!(
+            optimized.borrow().prev[0].borrow().op.as_deref(),
+            Some("powf()")
+        );
+    }
+
+    #[test]
+    fn removes_identities() {
+        let x = Value::from(3.0);
+        let y = (&x * 1.0 + 0.0).tanh() / Value::from(1.0) * Value::from(4.0);
+
+        assert_eq!(check(&y, std::slice::from_ref(&x)), 3);
+
+        // an identity at the root leaves just the leaf
+        let z = &x * 1.0;
+        assert_eq!(optimize(&z, std::slice::from_ref(&x)), x);
+    }
+
+    #[test]
+    fn shares_common_subexpressions() {
+        let x = Value::from(3.0);
+        let w = Value::from(-0.5);
+        let a = (&x * &w).tanh();
+        let b = (&x * &w).tanh();
+        let y = &a * &b + a.exp();
+
+        // x, w, x * w, tanh, tanh * tanh, exp and the sum
+        assert_eq!(check(&y, &[x.clone(), w.clone()]), 7);
+    }
+
+    #[test]
+    fn keeps_hooked_ops() {
+        // gradient reversal through an identity op
+        let a = Value::from(3.0);
+        let b = &a + 0.0;
+        b.register_hook(|grad| -grad);
+        let y = &b * &b;
+        assert_eq!(check(&y, std::slice::from_ref(&a)), 3);
+
+        // a duplicate of an op with a hook doesn't go through the hook
+        let x = Value::from(0.5);
+        let c = x.tanh();
+        c.register_hook(|grad| grad * 10.0);
+        let y = &c + x.tanh();
+        assert_eq!(check(&y, std::slice::from_ref(&x)), 4);
+    }
+
+    #[test]
+    fn keeps_hooked_sum() {
+        // the constants of the sum would otherwise fold away, leaving just x
+        let x = Value::from(3.0);
+        let y: Value = [x.clone(), Value::from(0.0), Value::from(0.0)].iter().sum();
+        y.register_hook(|grad| -grad);
+        let z = &y * &y;
+
+        check(&z, std::slice::from_ref(&x));
+        assert_eq!(x.borrow().grad, -6.0);
+    }
+
+    #[test]
+    fn custom_ops_not_shared() {
+        struct Tanh;
+        impl Op for Tanh {
+            fn name(&self) -> String {
+                "tanh()".to_string()
+            }
+            fn forward(&self, inputs: &[f64]) -> f64 {
+                inputs[0].tanh()
+            }
+            fn backward(&self, _inputs: &[f64], output: f64, grad: f64) -> Vec<f64> {
+                vec![grad * (1.0 - output * output) * 2.0]
+            }
+        }
+
+        let x = Value::from(0.5);
+        let y = Value::apply(Tanh, std::slice::from_ref(&x)) + x.tanh();
+        assert_eq!(check(&y, std::slice::from_ref(&x)), 4);
+    }
+
+    #[test]
+    fn custom_op_named_like_built_in() {
+        // not a multiplication, so `x * 1` isn't an identity and 5 can't be stored on it
+        struct MulPlusOne;
+        impl Op for MulPlusOne {
+            fn name(&self) -> String {
+                "*".to_string()
+            }
+            fn forward(&self, inputs: &[f64]) -> f64 {
+                inputs[0] * inputs[1] + 1.0
+            }
+            fn backward(&self, inputs: &[f64], _output: f64, grad: f64) -> Vec<f64> {
+                vec![grad * inputs[1], grad * inputs[0]]
+            }
+        }
+
+        let x = Value::from(3.0);
+        let y = Value::apply(MulPlusOne, &[x.clone(), Value::from(1.0)]).tanh();
+        assert_eq!(check(&y, std::slice::from_ref(&x)), 4);
+
+        let x = Value::from(3.0);
+        let y = Value::apply(MulPlusOne, &[x.clone(), Value::from(5.0)]).tanh();
+        assert_eq!(check(&y, std::slice::from_ref(&x)), 4);
+    }
+
+    #[test]
+    fn inside_no_grad() {
+        let x = Value::from(3.0);
+        let y = (&x * 1.0).tanh() * 2.0;
+
+        let optimized = crate::value::no_grad(|| optimize(&y, std::slice::from_ref(&x)));
+        assert_eq!(optimized.nodes().count(), 3);
+    }
+
+    #[test]
+    fn everything_constant() {
+        let x = Value::from(3.0);
+        let y = (&x * 2.0).exp();
+
+        let optimized = optimize(&y, &[]);
+        assert!(optimized.is_leaf());
+        assert_eq!(optimized.borrow().data, y.borrow().data);
+    }
+
+    #[test]
+    fn mlp_loss() {
+        let mlp = MLP::new(3, vec![4, 4, 1]);
+        let params = mlp.parameters();
+
+        // inputs and targets are constants, so every `x * w` is stored on its op
+        let xs = [[2.0, 3.0, -1.0], [3.0, -1.0, 0.5]];
+        let ys = [1.0, -1.0];
+        let loss: Value = xs
+            .iter()
+            .zip(ys)
+            .map(|(x, y)| {
+                let x = x.iter().map(|&x| Value::from(x)).collect();
+                (mlp.forward(x)[0].clone() - Value::from(y)).pow(Value::from(2.0))
+            })
+            .sum();
+
+        let before = loss.nodes().count();
+        let after = check(&loss, &params);
+        assert!(after < before);
+    }
+}
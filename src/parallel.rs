@@ -0,0 +1,128 @@
+// `Value`s are built on `Rc<RefCell<...>>` and can't be shared between threads, so
+// instead every worker thread builds its own copy of the model from an
+// `MLPSnapshot`, accumulates the gradients of its share of the examples into its
+// copy's parameters, and sends them back as plain `f64`s to be summed.
+
+use std::thread;
+
+use crate::neural::MLP;
+use crate::Value;
+
+/// Computes the summed `loss` over `examples` with the forward and backward passes
+/// split across `threads` worker threads. The gradients are added to the grads of
+/// `mlp`'s parameters, as if `backward` had been called on each example's loss.
+/// Returns the total loss.
+///
+/// `loss` is called on a worker thread's copy of the model, so it must build its
+/// inputs and targets from `examples` rather than capture existing `Value`s.
+#[allow(dead_code)]
+pub fn parallel_backward<E: Sync>(
+    mlp: &MLP,
+    examples: &[E],
+    threads: usize,
+    loss: impl Fn(&MLP, &E) -> Value + Sync,
+) -> f64 {
+    let snapshot = mlp.snapshot();
+    let chunk_size = examples.len().div_ceil(threads.max(1)).max(1);
+
+    let results: Vec<(f64, Vec<f64>)> = thread::scope(|scope| {
+        let workers: Vec<_> = examples
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let snapshot = &snapshot;
+                let loss = &loss;
+                scope.spawn(move || {
+                    let replica = snapshot.build(Value::from);
+
+                    let mut total_loss = 0.0;
+                    for example in chunk {
+                        let example_loss = loss(&replica, example);
+                        example_loss.backward();
+                        total_loss += example_loss.borrow().data;
+                    }
+
+                    let grads = replica
+                        .parameters()
+                        .iter()
+                        .map(|param| param.borrow().grad)
+                        .collect();
+                    (total_loss, grads)
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .collect()
+    });
+
+    // reduce the per thread gradients into the parameters
+    let params = mlp.parameters();
+    let mut total_loss = 0.0;
+    for (worker_loss, grads) in results {
+        total_loss += worker_loss;
+        for (param, grad) in params.iter().zip(grads) {
+            param.borrow_mut().grad += grad;
+        }
+    }
+
+    total_loss
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn squared_error(mlp: &MLP, (x, y): &(Vec<f64>, f64)) -> Value {
+        let inputs = x.iter().map(|&x| Value::from(x)).collect();
+        let pred = mlp.forward(inputs)[0].clone();
+        (pred - *y).powi(2)
+    }
+
+    #[test]
+    fn parallel_matches_serial() {
+        let mlp = MLP::new(3, vec![4, 4, 1]);
+        let examples: Vec<(Vec<f64>, f64)> = (0..10)
+            .map(|i| {
+                let i = i as f64;
+                (
+                    vec![i.sin(), i.cos(), i / 10.0],
+                    if i % 2.0 == 0.0 { 1.0 } else { -1.0 },
+                )
+            })
+            .collect();
+
+        // serial
+        let mut serial_loss = 0.0;
+        for example in examples.iter() {
+            let loss = squared_error(&mlp, example);
+            loss.backward();
+            serial_loss += loss.borrow().data;
+        }
+        let serial_grads: Vec<f64> = mlp.parameters().iter().map(|p| p.borrow().grad).collect();
+
+        // parallel
+        mlp.zero_grad();
+        let parallel_loss = parallel_backward(&mlp, &examples, 4, squared_error);
+        let parallel_grads: Vec<f64> = mlp.parameters().iter().map(|p| p.borrow().grad).collect();
+
+        assert!((serial_loss - parallel_loss).abs() < 1e-12);
+        assert_eq!(serial_grads.len(), parallel_grads.len());
+        for (serial, parallel) in serial_grads.iter().zip(parallel_grads.iter()) {
+            assert!((serial - parallel).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mlp = MLP::new(2, vec![3, 1]);
+        let copy = mlp.snapshot().build(Value::from);
+
+        let inputs = || vec![Value::from(0.5), Value::from(-1.5)];
+        assert_eq!(
+            mlp.forward(inputs())[0].borrow().data,
+            copy.forward(inputs())[0].borrow().data
+        );
+    }
+}
@@ -0,0 +1,290 @@
+// The scalar forward and backward rules of the built in ops, written once and shared
+// by `Value` and `CompiledGraph`. `Value` additionally has a graph building version
+// of each backward rule for `grad_graph`, which works on `Value`s rather than floats.
+
+use crate::float::Float;
+
+/// A built in op, with the constant it stores if it has one.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Rule<T: Float = f64> {
+    Add,
+    AddConst(T),
+    Sub,
+    SubConst(T),
+    RSub(T),
+    Mul,
+    MulConst(T),
+    Div,
+    DivConst(T),
+    RDiv(T),
+    Neg,
+    Sum,
+    Pow,
+    Powi(i32),
+    Powf(T),
+    Relu,
+    Ln,
+    Tanh,
+    Exp,
+    Log2,
+    Sqrt,
+    Sigmoid,
+    Softplus,
+    Sin,
+    Cos,
+    Abs,
+    LeakyRelu(T),
+    Elu,
+    Gelu,
+    Silu,
+    Max,
+    Min,
+    ClampMin(T),
+    ClampMax(T),
+    Select(bool),
+}
+
+impl<T: Float> Rule<T> {
+    /// The rule of the built in op named `op` with `inputs` inputs and the stored
+    /// `constant`, as read from a value's `op`, `prev` and `constant`.
+    pub(crate) fn new(op: &str, inputs: usize, constant: Option<T>) -> Option<Rule<T>> {
+        let rule = match (op, inputs, constant) {
+            ("+", 1, Some(c)) => Rule::AddConst(c),
+            ("+", 2, None) => Rule::Add,
+            ("+", _, None) => Rule::Sum,
+            ("-", 1, Some(c)) => Rule::SubConst(c),
+            ("-", 2, None) => Rule::Sub,
+            ("rsub", 1, Some(c)) => Rule::RSub(c),
+            ("*", 1, Some(c)) => Rule::MulConst(c),
+            ("*", 2, None) => Rule::Mul,
+            ("/", 1, Some(c)) => Rule::DivConst(c),
+            ("/", 2, None) => Rule::Div,
+            ("rdiv", 1, Some(c)) => Rule::RDiv(c),
+            ("neg", 1, None) => Rule::Neg,
+            ("pow()", 2, None) => Rule::Pow,
+            ("powi()", 1, Some(c)) => Rule::Powi(c.to_f64() as i32),
+            ("powf()", 1, Some(c)) => Rule::Powf(c),
+            ("ReLU", 1, None) => Rule::Relu,
+            ("ln()", 1, None) => Rule::Ln,
+            ("tanh()", 1, None) => Rule::Tanh,
+            ("exp()", 1, None) => Rule::Exp,
+            ("log2()", 1, None) => Rule::Log2,
+            ("sqrt()", 1, None) => Rule::Sqrt,
+            ("sigmoid()", 1, None) => Rule::Sigmoid,
+            ("softplus()", 1, None) => Rule::Softplus,
+            ("sin()", 1, None) => Rule::Sin,
+            ("cos()", 1, None) => Rule::Cos,
+            ("abs()", 1, None) => Rule::Abs,
+            ("leaky_relu()", 1, Some(c)) => Rule::LeakyRelu(c),
+            ("elu()", 1, None) => Rule::Elu,
+            ("gelu()", 1, None) => Rule::Gelu,
+            ("silu()", 1, None) => Rule::Silu,
+            ("max()", 2, None) => Rule::Max,
+            ("min()", 2, None) => Rule::Min,
+            ("clamp_min()", 1, Some(c)) => Rule::ClampMin(c),
+            ("clamp_max()", 1, Some(c)) => Rule::ClampMax(c),
+            ("select()", 2, Some(c)) => Rule::Select(c != T::ZERO),
+            _ => return None,
+        };
+        Some(rule)
+    }
+
+    /// The op's output given the data of its inputs `x`.
+    pub(crate) fn forward(&self, x: &[T]) -> T {
+        match *self {
+            Rule::Add => x[0] + x[1],
+            Rule::AddConst(c) => x[0] + c,
+            Rule::Sub => x[0] - x[1],
+            Rule::SubConst(c) => x[0] - c,
+            Rule::RSub(c) => c - x[0],
+            Rule::Mul => x[0] * x[1],
+            Rule::MulConst(c) => x[0] * c,
+            Rule::Div => x[0] / x[1],
+            Rule::DivConst(c) => x[0] / c,
+            Rule::RDiv(c) => c / x[0],
+            Rule::Neg => -x[0],
+            Rule::Sum => x.iter().copied().sum(),
+            Rule::Pow => x[0].powf(x[1]),
+            Rule::Powi(n) => x[0].powi(n),
+            Rule::Powf(n) => x[0].powf(n),
+            Rule::Relu => x[0].max(T::ZERO),
+            Rule::Ln => x[0].ln(),
+            Rule::Tanh => x[0].tanh(),
+            Rule::Exp => x[0].exp(),
+            Rule::Log2 => x[0].log2(),
+            Rule::Sqrt => x[0].sqrt(),
+            Rule::Sigmoid => sigmoid(x[0]),
+            // ln(1 + e^x), computed in a way that doesn't overflow for large inputs
+            Rule::Softplus => x[0].max(T::ZERO) + (-x[0].abs()).exp().ln_1p(),
+            Rule::Sin => x[0].sin(),
+            Rule::Cos => x[0].cos(),
+            Rule::Abs => x[0].abs(),
+            Rule::LeakyRelu(alpha) => {
+                if x[0] > T::ZERO {
+                    x[0]
+                } else {
+                    alpha * x[0]
+                }
+            }
+            Rule::Elu => {
+                if x[0] > T::ZERO {
+                    x[0]
+                } else {
+                    x[0].exp_m1()
+                }
+            }
+            Rule::Gelu => {
+                let (c, k, half) = gelu_consts::<T>();
+                let x = x[0];
+                half * x * (T::ONE + (c * (x + k * x.powi(3))).tanh())
+            }
+            Rule::Silu => x[0] / (T::ONE + (-x[0]).exp()),
+            Rule::Max => x[0].max(x[1]),
+            Rule::Min => x[0].min(x[1]),
+            Rule::ClampMin(lo) => x[0].max(lo),
+            Rule::ClampMax(hi) => x[0].min(hi),
+            Rule::Select(cond) => {
+                if cond {
+                    x[0]
+                } else {
+                    x[1]
+                }
+            }
+        }
+    }
+
+    /// Calls `add_grad` with the gradient of each input, given the data of the inputs
+    /// `x`, the op's output `out` and the gradient `grad` flowing into it. Inputs that
+    /// get no gradient, such as the unchosen operand of `max`, may be skipped.
+    pub(crate) fn backward(&self, x: &[T], out: T, grad: T, mut add_grad: impl FnMut(usize, T)) {
+        match *self {
+            Rule::Add => {
+                add_grad(0, grad);
+                add_grad(1, grad);
+            }
+            Rule::AddConst(_) | Rule::SubConst(_) => add_grad(0, grad),
+            Rule::Sub => {
+                add_grad(0, grad);
+                add_grad(1, -grad);
+            }
+            Rule::RSub(_) | Rule::Neg => add_grad(0, -grad),
+            Rule::Mul => {
+                add_grad(0, grad * x[1]);
+                add_grad(1, grad * x[0]);
+            }
+            Rule::MulConst(c) => add_grad(0, grad * c),
+            Rule::Div => {
+                add_grad(0, grad / x[1]);
+                add_grad(1, -grad * x[0] / x[1].powi(2));
+            }
+            Rule::DivConst(c) => add_grad(0, grad / c),
+            Rule::RDiv(c) => add_grad(0, -grad * c / x[0].powi(2)),
+            Rule::Sum => (0..x.len()).for_each(|i| add_grad(i, grad)),
+            Rule::Pow => {
+                let (base, exp) = (x[0], x[1]);
+                let exp_grad = if base == T::ZERO {
+                    T::ZERO
+                } else {
+                    base.ln() * out
+                };
+                add_grad(0, grad * exp * base.powf(exp - T::ONE));
+                add_grad(1, grad * exp_grad);
+            }
+            Rule::Powi(n) => add_grad(0, grad * powi_slope(x[0], n)),
+            Rule::Powf(n) => add_grad(0, grad * powf_slope(x[0], n)),
+            Rule::Relu => {
+                if out > T::ZERO {
+                    add_grad(0, grad);
+                }
+            }
+            Rule::Ln => add_grad(0, grad / x[0]),
+            Rule::Tanh => add_grad(0, grad * (T::ONE - out * out)),
+            Rule::Exp => add_grad(0, grad * out),
+            Rule::Log2 => add_grad(0, grad / (x[0] * T::LN_2)),
+            Rule::Sqrt => add_grad(0, grad / (T::from_f64(2.0) * out)),
+            Rule::Sigmoid => add_grad(0, grad * out * (T::ONE - out)),
+            Rule::Softplus => add_grad(0, grad / (T::ONE + (-x[0]).exp())),
+            Rule::Sin => add_grad(0, grad * x[0].cos()),
+            Rule::Cos => add_grad(0, -grad * x[0].sin()),
+            Rule::Abs => add_grad(0, grad * sign(x[0])),
+            Rule::LeakyRelu(alpha) => add_grad(0, if x[0] > T::ZERO { grad } else { grad * alpha }),
+            Rule::Elu => add_grad(
+                0,
+                if x[0] > T::ZERO {
+                    grad
+                } else {
+                    grad * (out + T::ONE)
+                },
+            ),
+            Rule::Gelu => {
+                let (c, k, half) = gelu_consts::<T>();
+                let three = T::from_f64(3.0);
+                let x = x[0];
+                let t = (c * (x + k * x.powi(3))).tanh();
+                let slope = half * (T::ONE + t)
+                    + half * x * (T::ONE - t * t) * c * (T::ONE + three * k * x * x);
+                add_grad(0, grad * slope);
+            }
+            Rule::Silu => {
+                let s = sigmoid(x[0]);
+                add_grad(0, grad * s * (T::ONE + x[0] * (T::ONE - s)));
+            }
+            Rule::Max => add_grad(if x[0] >= x[1] { 0 } else { 1 }, grad),
+            Rule::Min => add_grad(if x[0] <= x[1] { 0 } else { 1 }, grad),
+            Rule::ClampMin(lo) => {
+                if x[0] >= lo {
+                    add_grad(0, grad);
+                }
+            }
+            Rule::ClampMax(hi) => {
+                if x[0] <= hi {
+                    add_grad(0, grad);
+                }
+            }
+            Rule::Select(cond) => add_grad(if cond { 0 } else { 1 }, grad),
+        }
+    }
+}
+
+/// The derivative of `x^n`, `n * x^(n - 1)`. For `n = 0` it is `0` everywhere, as
+/// the formula would give `0 * inf = NaN` at `x = 0`.
+pub(crate) fn powi_slope<T: Float>(x: T, n: i32) -> T {
+    if n == 0 {
+        T::ZERO
+    } else {
+        T::from_f64(n as f64) * x.powi(n - 1)
+    }
+}
+
+/// Like `powi_slope`, for a float exponent.
+pub(crate) fn powf_slope<T: Float>(x: T, n: T) -> T {
+    if n == T::ZERO {
+        T::ZERO
+    } else {
+        n * x.powf(n - T::ONE)
+    }
+}
+
+fn sigmoid<T: Float>(x: T) -> T {
+    T::ONE / (T::ONE + (-x).exp())
+}
+
+// sqrt(2 / pi) and the cubic coefficient of the tanh approximation of gelu
+const GELU_C: f64 = 0.7978845608028654;
+const GELU_K: f64 = 0.044715;
+
+/// `GELU_C`, `GELU_K` and `0.5` in the value's float type.
+pub(crate) fn gelu_consts<T: Float>() -> (T, T, T) {
+    (T::from_f64(GELU_C), T::from_f64(GELU_K), T::from_f64(0.5))
+}
+
+/// The subgradient of `abs`: the sign of `x`, or `0` at zero.
+pub(crate) fn sign<T: Float>(x: T) -> T {
+    if x > T::ZERO {
+        T::ONE
+    } else if x < T::ZERO {
+        -T::ONE
+    } else {
+        T::ZERO
+    }
+}
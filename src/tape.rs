@@ -0,0 +1,353 @@
+// An alternative autograd backend to `Value`. Instead of every value being its own
+// heap allocation that points at its inputs, values live in contiguous vectors on a
+// `Tape` in the order they were created, and are referred to by `Copy` indices. As a
+// value's inputs always come before it, backward is a single reverse sweep over the
+// tape with no topological sort.
+
+use std::cell::RefCell;
+use std::iter::Sum;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+use crate::neural::Scalar;
+use crate::rule::powi_slope;
+
+/// A value's inputs and the partial derivatives of the value with respect to each
+/// of them, computed when the value was created. Leaves and unary ops point their
+/// unused inputs at themselves with a partial of zero.
+#[derive(Clone, Copy)]
+struct Node {
+    inputs: [usize; 2],
+    partials: [f64; 2],
+}
+
+#[derive(Default)]
+pub struct Tape {
+    nodes: RefCell<Vec<Node>>,
+    data: RefCell<Vec<f64>>,
+    grad: RefCell<Vec<f64>>,
+}
+
+#[allow(dead_code)]
+impl Tape {
+    pub fn new() -> Self {
+        Tape::default()
+    }
+
+    /// Creates a leaf value on the tape.
+    pub fn var(&self, data: f64) -> Var<'_> {
+        let index = self.len();
+        self.push(data, [index, index], [0.0, 0.0])
+    }
+
+    fn push(&self, data: f64, inputs: [usize; 2], partials: [f64; 2]) -> Var<'_> {
+        let mut nodes = self.nodes.borrow_mut();
+        nodes.push(Node { inputs, partials });
+        self.data.borrow_mut().push(data);
+        self.grad.borrow_mut().push(0.0);
+
+        Var {
+            tape: self,
+            index: nodes.len() - 1,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets every value created after the first `len`. Create a model's parameters
+    /// first and truncate back to them after each training step, so the tape doesn't
+    /// keep growing. `Var`s pointing past `len` must not be used afterwards.
+    pub fn truncate(&self, len: usize) {
+        self.nodes.borrow_mut().truncate(len);
+        self.data.borrow_mut().truncate(len);
+        self.grad.borrow_mut().truncate(len);
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Var<'t> {
+    tape: &'t Tape,
+    index: usize,
+}
+
+#[allow(dead_code)]
+impl<'t> Var<'t> {
+    pub fn data(&self) -> f64 {
+        self.tape.data.borrow()[self.index]
+    }
+
+    pub fn set_data(&self, data: f64) {
+        self.tape.data.borrow_mut()[self.index] = data;
+    }
+
+    pub fn grad(&self) -> f64 {
+        self.tape.grad.borrow()[self.index]
+    }
+
+    /// Computes the gradient of `self` with respect to every value created before
+    /// it. Unlike `Value::backward`, gradients don't accumulate across calls: every
+    /// grad on the tape is reset first.
+    pub fn backward(&self) {
+        let nodes = self.tape.nodes.borrow();
+        let mut grad = self.tape.grad.borrow_mut();
+
+        grad.iter_mut().for_each(|grad| *grad = 0.0);
+        grad[self.index] = 1.0;
+        for index in (0..=self.index).rev() {
+            let node = nodes[index];
+            let node_grad = grad[index];
+            grad[node.inputs[0]] += node.partials[0] * node_grad;
+            grad[node.inputs[1]] += node.partials[1] * node_grad;
+        }
+    }
+
+    fn unary(&self, data: f64, partial: f64) -> Var<'t> {
+        self.tape
+            .push(data, [self.index, self.index], [partial, 0.0])
+    }
+
+    fn binary(&self, other: &Var<'t>, data: f64, partials: [f64; 2]) -> Var<'t> {
+        assert!(
+            std::ptr::eq(self.tape, other.tape),
+            "can't combine Vars from different tapes"
+        );
+        self.tape.push(data, [self.index, other.index], partials)
+    }
+
+    pub fn relu(&self) -> Var<'t> {
+        let x = self.data();
+        if x > 0.0 {
+            self.unary(x, 1.0)
+        } else {
+            self.unary(0.0, 0.0)
+        }
+    }
+
+    pub fn tanh(&self) -> Var<'t> {
+        let tanh = self.data().tanh();
+        self.unary(tanh, 1.0 - tanh * tanh)
+    }
+
+    pub fn exp(&self) -> Var<'t> {
+        let exp = self.data().exp();
+        self.unary(exp, exp)
+    }
+
+    pub fn powi(&self, n: i32) -> Var<'t> {
+        let x = self.data();
+        self.unary(x.powi(n), powi_slope(x, n))
+    }
+}
+
+impl<'t> Add for Var<'t> {
+    type Output = Var<'t>;
+
+    fn add(self, other: Var<'t>) -> Var<'t> {
+        self.binary(&other, self.data() + other.data(), [1.0, 1.0])
+    }
+}
+
+impl<'t> Sub for Var<'t> {
+    type Output = Var<'t>;
+
+    fn sub(self, other: Var<'t>) -> Var<'t> {
+        self.binary(&other, self.data() - other.data(), [1.0, -1.0])
+    }
+}
+
+impl<'t> Mul for Var<'t> {
+    type Output = Var<'t>;
+
+    fn mul(self, other: Var<'t>) -> Var<'t> {
+        let (a, b) = (self.data(), other.data());
+        self.binary(&other, a * b, [b, a])
+    }
+}
+
+impl<'t> Div for Var<'t> {
+    type Output = Var<'t>;
+
+    fn div(self, other: Var<'t>) -> Var<'t> {
+        let (a, b) = (self.data(), other.data());
+        self.binary(&other, a / b, [1.0 / b, -a / (b * b)])
+    }
+}
+
+impl<'t> Neg for Var<'t> {
+    type Output = Var<'t>;
+
+    fn neg(self) -> Var<'t> {
+        self.unary(-self.data(), -1.0)
+    }
+}
+
+impl<'t> Add<f64> for Var<'t> {
+    type Output = Var<'t>;
+
+    fn add(self, other: f64) -> Var<'t> {
+        self.unary(self.data() + other, 1.0)
+    }
+}
+
+impl<'t> Sub<f64> for Var<'t> {
+    type Output = Var<'t>;
+
+    fn sub(self, other: f64) -> Var<'t> {
+        self.unary(self.data() - other, 1.0)
+    }
+}
+
+impl<'t> Mul<f64> for Var<'t> {
+    type Output = Var<'t>;
+
+    fn mul(self, other: f64) -> Var<'t> {
+        self.unary(self.data() * other, other)
+    }
+}
+
+impl<'t> Div<f64> for Var<'t> {
+    type Output = Var<'t>;
+
+    fn div(self, other: f64) -> Var<'t> {
+        self.unary(self.data() / other, 1.0 / other)
+    }
+}
+
+// `Var`s are `Copy`, so these only exist to satisfy `Scalar`'s bounds
+impl<'a, 't> Add<&'a Var<'t>> for Var<'t> {
+    type Output = Var<'t>;
+
+    fn add(self, other: &'a Var<'t>) -> Var<'t> {
+        self + *other
+    }
+}
+
+impl<'a, 't> Mul<&'a Var<'t>> for &'a Var<'t> {
+    type Output = Var<'t>;
+
+    fn mul(self, other: &'a Var<'t>) -> Var<'t> {
+        *self * *other
+    }
+}
+
+/// Adds the values together pairwise. Panics on an empty iterator, as there is no
+/// tape to create the zero on.
+impl<'t> Sum for Var<'t> {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.reduce(|sum, var| sum + var)
+            .expect("can't sum an empty iterator of Vars")
+    }
+}
+
+impl<'t> Scalar for Var<'t> {
+    fn data(&self) -> f64 {
+        Var::data(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::neural::MLP;
+    use crate::Value;
+
+    #[test]
+    fn ops() {
+        let tape = Tape::new();
+        let a = tape.var(3.0);
+        let b = tape.var(4.0);
+        let c = (a * b + a / b - b).tanh() * 2.0 + (-a).powi(2) - a.exp() / 10.0;
+
+        c.backward();
+
+        let va = Value::from(3.0);
+        let vb = Value::from(4.0);
+        let vc = (&va * &vb + &va / &vb - &vb).tanh() * 2.0 + (-&va).powi(2) - va.exp() / 10.0;
+        vc.backward();
+
+        assert!((c.data() - vc.borrow().data).abs() < 1e-12);
+        assert!((a.grad() - va.borrow().grad).abs() < 1e-12);
+        assert!((b.grad() - vb.borrow().grad).abs() < 1e-12);
+    }
+
+    #[test]
+    fn powi_zero_at_zero() {
+        let tape = Tape::new();
+        let a = tape.var(0.0);
+        let b = a.powi(0);
+
+        b.backward();
+
+        assert_eq!(b.data(), 1.0);
+        assert_eq!(a.grad(), 0.0);
+    }
+
+    #[test]
+    fn backward_resets_grads() {
+        let tape = Tape::new();
+        let a = tape.var(3.0);
+        let b = a * a;
+
+        b.backward();
+        b.backward();
+
+        assert_eq!(a.grad(), 6.0);
+    }
+
+    #[test]
+    fn truncate_keeps_parameters() {
+        let tape = Tape::new();
+        let a = tape.var(3.0);
+        let len = tape.len();
+
+        for _ in 0..3 {
+            let b = a * 2.0;
+            b.backward();
+            assert_eq!(a.grad(), 2.0);
+            tape.truncate(len);
+        }
+
+        assert_eq!(tape.len(), 1);
+    }
+
+    #[test]
+    fn mlp_matches_value_backend() {
+        // give both MLPs the same parameters
+        let mut k = 0.0;
+        let value_mlp = MLP::with_init(3, vec![4, 4, 1], |_| {
+            k += 1.0;
+            Value::from(f64::sin(k))
+        });
+
+        let tape = Tape::new();
+        let mut k = 0.0;
+        let tape_mlp = MLP::with_init(3, vec![4, 4, 1], |_| {
+            k += 1.0;
+            tape.var(f64::sin(k))
+        });
+
+        let xs = [2.0, 3.0, -1.0];
+
+        let value_out = value_mlp.forward(xs.iter().map(|&x| Value::from(x)).collect())[0].clone();
+        let value_loss = (value_out - 1.0).powi(2);
+        value_loss.backward();
+
+        let tape_out = tape_mlp.forward(xs.iter().map(|&x| tape.var(x)).collect())[0];
+        let tape_loss = (tape_out - 1.0).powi(2);
+        tape_loss.backward();
+
+        assert!((value_loss.borrow().data - tape_loss.data()).abs() < 1e-12);
+        for (value_param, tape_param) in value_mlp
+            .parameters()
+            .iter()
+            .zip(tape_mlp.parameters().iter())
+        {
+            assert!((value_param.borrow().grad - tape_param.grad()).abs() < 1e-12);
+        }
+    }
+}
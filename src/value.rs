@@ -1,348 +1,2146 @@
-use std::collections::HashSet;
+use crate::anomaly;
+use crate::float::Float;
+use crate::id::Id;
+use crate::op::Op;
+use crate::rule::{gelu_consts, sign, Rule};
+use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Debug};
 use std::hash::Hash;
 use std::hash::Hasher;
-use std::iter::Sum;
-use std::ops::{self, Add, Div, Mul, Sub};
-use std::{cell::RefCell, rc::Rc};
-use uuid::Uuid;
+use std::iter::{Product, Sum};
+use std::ops::{self, Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign};
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
 
 #[derive(Debug)]
-pub struct ValueData {
-    pub data: f64,
-    pub grad: f64,
-    pub backward: Option<fn(value: &ValueData)>,
-    pub prev: Vec<Value>,
+pub struct ValueData<T: Float = f64> {
+    pub data: T,
+    pub grad: T,
+    pub backward: Option<fn(value: &ValueData<T>)>,
+    /// Like `backward`, but builds the gradients of `prev` as new `Value`s so that
+    /// they can themselves be differentiated.
+    pub backward_graph: Option<BackwardGraph<T>>,
+    pub prev: Vec<Value<T>>,
     pub op: Option<String>,
-    pub uuid: Uuid,
-    pub subgraph_id: Option<Uuid>,
+    /// A constant operand of `op` that is stored on the value itself rather than as a
+    /// separate input, such as the slope of `leaky_relu`.
+    pub constant: Option<T>,
+    /// The user defined op that produced this value, if any. See `Value::apply`.
+    pub custom_op: Option<Rc<dyn Op<T>>>,
+    /// Run during `backward` on the gradient flowing into this value, before it is
+    /// passed on to `prev`. See `Value::register_hook`.
+    pub hooks: Vec<GradHook<T>>,
+    /// Keeps this value's grad after `backward_leaf_grads`, even if it isn't a leaf.
+    pub retain_grad: bool,
+    /// Set once `backward_and_free` has dropped this value's link to its inputs.
+    pub freed: bool,
+    pub id: Id,
+    pub subgraph_id: Option<Id>,
+    /// A name for the value, such as `w0` or `loss`, shown when it is printed or
+    /// rendered. See `Value::named`.
+    pub label: Option<String>,
 }
 
-impl ValueData {
-    fn new(data: f64) -> ValueData {
+/// Given a value and the gradient flowing into it, builds the gradients of its inputs.
+pub type BackwardGraph<T> = fn(value: &Value<T>, grad: &Value<T>) -> Vec<Value<T>>;
+
+#[derive(Clone)]
+pub struct GradHook<T>(Rc<dyn Fn(T) -> T>);
+
+impl<T> Debug for GradHook<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "GradHook")
+    }
+}
+
+impl<T: Float> ValueData<T> {
+    pub(crate) fn new(data: T) -> ValueData<T> {
         ValueData {
             data,
-            grad: 0.0,
+            grad: T::ZERO,
             backward: None,
+            backward_graph: None,
             prev: Vec::new(),
             op: None,
-            uuid: Uuid::new_v4(),
+            constant: None,
+            custom_op: None,
+            hooks: Vec::new(),
+            retain_grad: false,
+            freed: false,
+            id: Id::next(),
             subgraph_id: None,
+            label: None,
+        }
+    }
+
+    /// Runs `rule`'s backward rule for this value, adding to the grads of `prev`.
+    fn backward_rule(&self, rule: Rule<T>) {
+        // read every input before writing any grad, as the same value may be passed
+        // more than once, without allocating for the usual one or two inputs
+        let mut stack = [T::ZERO; 2];
+        let heap: Vec<T>;
+        let x = if self.prev.len() <= stack.len() {
+            for (x, prev) in stack.iter_mut().zip(self.prev.iter()) {
+                *x = prev.borrow().data;
+            }
+            &stack[..self.prev.len()]
+        } else {
+            heap = self.prev.iter().map(|prev| prev.borrow().data).collect();
+            &heap
+        };
+        rule.backward(x, self.data, self.grad, |i, grad| {
+            self.prev[i].borrow_mut().grad += grad
+        });
+    }
+}
+
+impl<T: Float> Drop for ValueData<T> {
+    fn drop(&mut self) {
+        // Dropping a long chain of values would otherwise recurse once per value and
+        // overflow the stack, so unlink the inputs of any value we hold the last
+        // reference to and drop them from here instead.
+        let mut stack = std::mem::take(&mut self.prev);
+        while let Some(value) = stack.pop() {
+            if let Ok(cell) = Rc::try_unwrap(value.0) {
+                stack.append(&mut cell.into_inner().prev);
+            }
         }
     }
 }
 
 #[derive(Clone)]
-pub struct Value(Rc<RefCell<ValueData>>);
+pub struct Value<T: Float = f64>(Rc<RefCell<ValueData<T>>>);
 
 // Lets us do `value.borrow().data` instead of `value.0.borrow().data`
-impl ops::Deref for Value {
-    type Target = Rc<RefCell<ValueData>>;
+impl<T: Float> ops::Deref for Value<T> {
+    type Target = Rc<RefCell<ValueData<T>>>;
     fn deref(&self) -> &Self::Target {
         &self.0
     }
 }
 
-impl PartialEq for Value {
+impl<T: Float> PartialEq for Value<T> {
     fn eq(&self, other: &Self) -> bool {
-        self.borrow().uuid == other.borrow().uuid
+        Rc::ptr_eq(&self.0, &other.0)
     }
 }
 
-impl Eq for Value {}
+impl<T: Float> Eq for Value<T> {}
 
-impl Hash for Value {
+impl<T: Float> Hash for Value<T> {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.borrow().uuid.hash(state);
+        std::ptr::hash(Rc::as_ptr(&self.0), state);
+    }
+}
+
+/// Returned when backpropagating through a graph that `Value::backward_and_free`
+/// has already freed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GraphFreedError;
+
+impl fmt::Display for GraphFreedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "can't backpropagate through a graph that was freed by backward_and_free"
+        )
+    }
+}
+
+impl std::error::Error for GraphFreedError {}
+
+thread_local! {
+    static GRAD_ENABLED: Cell<bool> = const { Cell::new(true) };
+}
+
+/// Runs `f` with graph building disabled on this thread, so every op inside it
+/// produces a leaf value holding only its `data`. Useful for inference, where the
+/// graph would never be backpropagated through.
+#[allow(dead_code)]
+pub fn no_grad<T>(f: impl FnOnce() -> T) -> T {
+    with_grad_enabled(false, f)
+}
+
+/// Runs `f` with graph building enabled even inside `no_grad`, for code that
+/// rebuilds an existing graph through the ops, such as loading one from JSON.
+pub(crate) fn enable_grad<T>(f: impl FnOnce() -> T) -> T {
+    with_grad_enabled(true, f)
+}
+
+fn with_grad_enabled<T>(enabled: bool, f: impl FnOnce() -> T) -> T {
+    // restores the previous state even if `f` panics
+    struct Guard(bool);
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            GRAD_ENABLED.with(|grad_enabled| grad_enabled.set(self.0));
+        }
     }
+
+    let _guard = Guard(GRAD_ENABLED.with(|grad_enabled| grad_enabled.replace(enabled)));
+    f()
+}
+
+/// Whether ops currently record their inputs, i.e. we aren't inside `no_grad`.
+pub fn is_grad_enabled() -> bool {
+    GRAD_ENABLED.with(|enabled| enabled.get())
 }
 
-impl Value {
-    fn new(value: ValueData) -> Value {
-        Value(Rc::new(RefCell::new(value)))
+impl<T: Float> Value<T> {
+    pub(crate) fn new(mut value: ValueData<T>) -> Value<T> {
+        let anomaly = anomaly::check_forward(&value);
+        if !is_grad_enabled() {
+            value.prev = Vec::new();
+            value.backward = None;
+            value.backward_graph = None;
+            value.op = None;
+            value.constant = None;
+            value.custom_op = None;
+        }
+        let value = Value(Rc::new(RefCell::new(value)));
+        if let Some(err) = anomaly {
+            anomaly::record_forward(err, &value);
+        }
+        value
+    }
+
+    /// Labels `self`, e.g. `Value::from(3.0).named("a")`, so it can be told apart when
+    /// printed, rendered or reported by `detect_anomaly`.
+    pub fn named(self, label: &str) -> Value<T> {
+        self.borrow_mut().label = Some(label.to_string());
+        self
+    }
+
+    /// Returns a new leaf value holding the same data as `self`, so that no gradient
+    /// flows back through it.
+    pub fn detach(&self) -> Value<T> {
+        Value::from(self.borrow().data)
     }
 
+    /// Computes the gradient of `self` with respect to every value it was computed
+    /// from, adding it to their `grad`s.
+    ///
+    /// Panics if part of the graph was freed by `backward_and_free`.
     pub fn backward(&self) {
+        if let Err(err) = self.run_backward(true, false) {
+            panic!("{}", err);
+        }
+    }
+
+    /// Like `backward`, but only leaves and values marked with `retain_grad` keep
+    /// their grad. Every other value's grad is reset to 0 as soon as it has been
+    /// passed on to its inputs, so stale intermediate grads don't build up across
+    /// backward passes.
+    ///
+    /// This doesn't free any memory, as a grad is stored inline on its value. Use
+    /// `backward_and_free` to let the intermediate values themselves be reclaimed.
+    pub fn backward_leaf_grads(&self) {
+        if let Err(err) = self.run_backward(false, false) {
+            panic!("{}", err);
+        }
+    }
+
+    /// Like `backward`, but afterwards every value in the graph apart from the leaves
+    /// drops its inputs and backward rules, so intermediate values can be reclaimed
+    /// even while handles to the output or other values are kept around. Their
+    /// `data`, `grad` and `op` are kept.
+    ///
+    /// Returns an error, without touching any grads, if part of the graph was
+    /// already freed.
+    pub fn backward_and_free(&self) -> Result<(), GraphFreedError> {
+        self.run_backward(true, true)
+    }
+
+    fn run_backward(
+        &self,
+        retain_all_grads: bool,
+        free_graph: bool,
+    ) -> Result<(), GraphFreedError> {
         let mut topo = self.build_topo();
         topo.reverse();
 
-        self.borrow_mut().grad = 1.0;
-        for v in topo {
+        if topo.iter().any(|v| v.borrow().freed) {
+            return Err(GraphFreedError);
+        }
+
+        // hooks only see the gradient added by this pass, not what `grad` already held
+        let grads_before: Vec<T> = topo
+            .iter()
+            .map(|v| if v == self { T::ZERO } else { v.borrow().grad })
+            .collect();
+
+        self.borrow_mut().grad = T::ONE;
+        for (v, grad_before) in topo.into_iter().zip(grads_before) {
+            v.run_hooks(self, grad_before);
             if let Some(backprop) = v.borrow().backward {
+                let grads_before = anomaly::grads_before(&v);
                 backprop(&v.borrow());
+                anomaly::check_backward(self, &v, grads_before);
+            }
+            if !retain_all_grads && !v.is_leaf() && !v.borrow().retain_grad {
+                v.borrow_mut().grad = T::ZERO;
+            }
+            if free_graph && !v.is_leaf() {
+                let mut v = v.borrow_mut();
+                v.prev = Vec::new();
+                v.backward = None;
+                v.backward_graph = None;
+                v.custom_op = None;
+                v.freed = true;
             }
         }
+
+        Ok(())
     }
 
-    fn build_topo(&self) -> Vec<Value> {
-        let mut topo: Vec<Value> = vec![];
-        let mut visited: HashSet<Value> = HashSet::new();
-        self._build_topo(&mut topo, &mut visited);
-        topo
+    /// Registers a hook that `backward` calls with the total gradient flowing into
+    /// this value during that pass. The hook returns the gradient to use instead,
+    /// which is what gets added to `grad` and passed on to the value's inputs. Return
+    /// the gradient unchanged to only observe it. Hooks run in the order they were
+    /// registered.
+    pub fn register_hook(&self, hook: impl Fn(T) -> T + 'static) {
+        self.borrow_mut().hooks.push(GradHook(Rc::new(hook)));
     }
 
-    fn _build_topo(&self, topo: &mut Vec<Value>, visited: &mut HashSet<Value>) {
-        if visited.insert(self.clone()) {
-            self.borrow().prev.iter().for_each(|child| {
-                child._build_topo(topo, visited);
-            });
-            topo.push(self.clone());
+    /// Keeps this value's grad after `backward_leaf_grads`.
+    pub fn retain_grad(&self) {
+        self.borrow_mut().retain_grad = true;
+    }
+
+    /// Runs the hooks on the gradient this pass added on top of `grad_before`, during
+    /// a backward pass started at `root`.
+    fn run_hooks(&self, root: &Value<T>, grad_before: T) {
+        // clone the hooks out so that they may borrow this value themselves
+        let hooks = self.borrow().hooks.clone();
+        if hooks.is_empty() {
+            return;
+        }
+        let incoming = self.borrow().grad - grad_before;
+        let mut grad = incoming;
+        for GradHook(hook) in hooks {
+            grad = hook(grad);
+        }
+        anomaly::check_hooks(root, self, incoming, grad);
+        self.borrow_mut().grad = grad_before + grad;
+    }
+
+    /// Whether this value was created directly rather than by an op.
+    pub fn is_leaf(&self) -> bool {
+        let v = self.borrow();
+        v.prev.is_empty() && !v.freed
+    }
+
+    /// Sets the grad of `self` and of every value it was computed from to 0.
+    pub fn zero_grad(&self) {
+        for v in self.build_topo() {
+            v.borrow_mut().grad = T::ZERO;
+        }
+    }
+
+    /// Returns the gradient of `self` with respect to each value in `wrt`, as
+    /// `Value`s that are part of a new differentiable graph. Unlike `backward`, this
+    /// doesn't touch any `grad` fields, so the results can be backpropagated through
+    /// to get higher order derivatives.
+    ///
+    /// Panics if part of the graph was freed by `backward_and_free`.
+    pub fn grad_graph(&self, wrt: &[Value<T>]) -> Vec<Value<T>> {
+        let mut topo = self.build_topo();
+        topo.reverse();
+
+        if topo.iter().any(|v| v.borrow().freed) {
+            panic!("{}", GraphFreedError);
+        }
+
+        let mut grads: HashMap<Value<T>, Value<T>> = HashMap::new();
+        grads.insert(self.clone(), Value::from(T::ONE));
+        for v in topo {
+            let Some(grad) = grads.get(&v).cloned() else {
+                continue;
+            };
+            let Some(backprop) = v.borrow().backward_graph else {
+                continue;
+            };
+            let prev = v.borrow().prev.clone();
+            for (prev, prev_grad) in prev.into_iter().zip(backprop(&v, &grad)) {
+                let total = match grads.remove(&prev) {
+                    Some(existing) => existing + prev_grad,
+                    None => prev_grad,
+                };
+                grads.insert(prev, total);
+            }
+        }
+
+        wrt.iter()
+            .map(|v| {
+                grads
+                    .get(v)
+                    .cloned()
+                    .unwrap_or_else(|| Value::from(T::ZERO))
+            })
+            .collect()
+    }
+
+    /// Returns every value in the graph ending at `self`, with each value placed after
+    /// all of its inputs. Uses an explicit stack so that deep graphs don't overflow
+    /// the thread's stack.
+    pub(crate) fn build_topo(&self) -> Vec<Value<T>> {
+        let mut topo: Vec<Value<T>> = vec![];
+        let mut visited: HashSet<Value<T>> = HashSet::new();
+
+        // the bool marks whether the value's inputs have already been pushed
+        let mut stack: Vec<(Value<T>, bool)> = vec![(self.clone(), false)];
+        while let Some((value, inputs_pushed)) = stack.pop() {
+            if inputs_pushed {
+                topo.push(value);
+                continue;
+            }
+            if !visited.insert(value.clone()) {
+                continue;
+            }
+
+            stack.push((value.clone(), true));
+            // push in reverse so that inputs are visited in order
+            for child in value.borrow().prev.iter().rev() {
+                if !visited.contains(child) {
+                    stack.push((child.clone(), false));
+                }
+            }
         }
+
+        topo
     }
 
     pub fn relu(&self) -> Self {
-        let mut new_value = ValueData::new(self.borrow().data.max(0.0));
+        let mut new_value = ValueData::new(Rule::Relu.forward(&[self.borrow().data]));
 
         new_value.prev = vec![self.clone()];
         new_value.op = Some(String::from("ReLU"));
-        new_value.backward = Some(|value: &ValueData| {
-            if value.data > 0.0 {
-                value.prev[0].borrow_mut().grad += value.grad;
-            }
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Relu));
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            let slope = if value.borrow().data > T::ZERO {
+                T::ONE
+            } else {
+                T::ZERO
+            };
+            vec![grad.clone() * slope]
         });
 
         Value::new(new_value)
     }
 
-    pub fn pow(&self, n: Value) -> Self {
-        let mut new_value = ValueData::new(self.borrow().data.powf(n.borrow().data));
+    /// Raises `self` to the power of `n`, propagating gradients to both the base and
+    /// the exponent.
+    ///
+    /// The exponent's gradient is `ln(base) * base^n`. For a base of zero it is taken
+    /// to be `0`, its limit as the base approaches zero from above. For negative bases
+    /// the derivative doesn't exist over the reals and the exponent's gradient is NaN.
+    pub fn pow(&self, n: Value<T>) -> Self {
+        let mut new_value =
+            ValueData::new(Rule::Pow.forward(&[self.borrow().data, n.borrow().data]));
 
         new_value.prev = vec![self.clone(), n];
         new_value.op = Some("pow()".to_string());
-        new_value.backward = Some(|value: &ValueData| {
-            let base = value.prev[0].borrow().data;
-            let exp = value.prev[1].borrow().data;
-            value.prev[0].borrow_mut().grad += value.grad * exp * base.powf(exp - 1.0);
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Pow));
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            let base = value.borrow().prev[0].clone();
+            let exp = value.borrow().prev[1].clone();
+            let exp_grad = if base.borrow().data == T::ZERO {
+                Value::from(T::ZERO)
+            } else {
+                grad.clone() * base.ln() * value.clone()
+            };
+            vec![
+                grad.clone() * exp.clone() * base.pow(exp - T::ONE),
+                exp_grad,
+            ]
         });
 
         Value::new(new_value)
     }
 
-    pub fn tanh(&self) -> Self {
-        let mut new_value = ValueData::new(self.borrow().data.tanh());
+    /// Raises `self` to a constant integer power, without creating a node for the
+    /// exponent. For `n = 0` the gradient is `0`, including at zero.
+    pub fn powi(&self, n: i32) -> Self {
+        let mut new_value = ValueData::new(Rule::Powi(n).forward(&[self.borrow().data]));
 
         new_value.prev = vec![self.clone()];
-        new_value.op = Some("tanh()".to_string());
-        new_value.backward = Some(|value: &ValueData| {
-            let tanh = value.data.tanh();
-            value.prev[0].borrow_mut().grad += value.grad * (1.0 - tanh * tanh);
+        new_value.op = Some("powi()".to_string());
+        new_value.constant = Some(T::from_f64(n as f64));
+        new_value.backward = Some(|value: &ValueData<T>| {
+            value.backward_rule(Rule::Powi(value.constant.unwrap().to_f64() as i32))
+        });
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            let x = value.borrow().prev[0].clone();
+            let n = value.borrow().constant.unwrap();
+            if n == T::ZERO {
+                // x^0 is constant, and n * x^-1 would be NaN at zero
+                return vec![grad.clone() * T::ZERO];
+            }
+            vec![grad.clone() * n * x.powi(n.to_f64() as i32 - 1)]
         });
 
         Value::new(new_value)
     }
-}
 
-impl<T: Into<f64>> From<T> for Value {
-    fn from(t: T) -> Value {
-        Value::new(ValueData::new(t.into()))
-    }
-}
+    /// Raises `self` to a constant power, without creating a node for the exponent.
+    /// For `n = 0` the gradient is `0`, including at zero.
+    pub fn powf(&self, n: T) -> Self {
+        let mut new_value = ValueData::new(Rule::Powf(n).forward(&[self.borrow().data]));
 
-impl Debug for Value {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let v = &self.borrow();
-        write!(f, "data={} grad={}", v.data, v.grad)
-    }
-}
+        new_value.prev = vec![self.clone()];
+        new_value.op = Some("powf()".to_string());
+        new_value.constant = Some(n);
+        new_value.backward =
+            Some(|value: &ValueData<T>| value.backward_rule(Rule::Powf(value.constant.unwrap())));
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            let x = value.borrow().prev[0].clone();
+            let n = value.borrow().constant.unwrap();
+            if n == T::ZERO {
+                return vec![grad.clone() * T::ZERO];
+            }
+            vec![grad.clone() * n * x.powf(n - T::ONE)]
+        });
 
-impl Add for Value {
-    type Output = Self;
+        Value::new(new_value)
+    }
 
-    fn add(self, other: Self) -> Self {
-        let mut new_value = ValueData::new(self.borrow().data + other.borrow().data);
+    pub fn ln(&self) -> Self {
+        let mut new_value = ValueData::new(Rule::Ln.forward(&[self.borrow().data]));
 
-        new_value.prev = vec![self, other];
-        new_value.op = Some(String::from("+"));
-        new_value.backward = Some(|value: &ValueData| {
-            value.prev[0].borrow_mut().grad += value.grad;
-            value.prev[1].borrow_mut().grad += value.grad;
+        new_value.prev = vec![self.clone()];
+        new_value.op = Some("ln()".to_string());
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Ln));
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            let x = value.borrow().prev[0].clone();
+            vec![grad.clone() / x]
         });
 
         Value::new(new_value)
     }
-}
-
-impl Sub for Value {
-    type Output = Self;
 
-    fn sub(self, other: Self) -> Self {
-        let mut new_value = ValueData::new(self.borrow().data - other.borrow().data);
+    pub fn tanh(&self) -> Self {
+        let mut new_value = ValueData::new(Rule::Tanh.forward(&[self.borrow().data]));
 
-        new_value.prev = vec![self, other];
-        new_value.op = Some(String::from("-"));
-        new_value.backward = Some(|value: &ValueData| {
-            value.prev[0].borrow_mut().grad += value.grad;
-            value.prev[1].borrow_mut().grad -= value.grad;
+        new_value.prev = vec![self.clone()];
+        new_value.op = Some("tanh()".to_string());
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Tanh));
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            vec![grad.clone() * value.powi(2).rsub(T::ONE)]
         });
 
         Value::new(new_value)
     }
-}
 
-impl Mul for Value {
-    type Output = Self;
+    pub fn exp(&self) -> Self {
+        let mut new_value = ValueData::new(Rule::Exp.forward(&[self.borrow().data]));
 
-    fn mul(self, other: Self) -> Self {
-        let mut new_value = ValueData::new(self.borrow().data * other.borrow().data);
+        new_value.prev = vec![self.clone()];
+        new_value.op = Some("exp()".to_string());
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Exp));
+        new_value.backward_graph =
+            Some(|value: &Value<T>, grad: &Value<T>| vec![grad.clone() * value.clone()]);
 
-        new_value.prev = vec![self, other];
-        new_value.op = Some(String::from("*"));
-        new_value.backward = Some(|value: &ValueData| {
-            value.prev[0].borrow_mut().grad += value.grad * value.prev[1].borrow().data;
-            value.prev[1].borrow_mut().grad += value.grad * value.prev[0].borrow().data;
+        Value::new(new_value)
+    }
+
+    pub fn log2(&self) -> Self {
+        let mut new_value = ValueData::new(Rule::Log2.forward(&[self.borrow().data]));
+
+        new_value.prev = vec![self.clone()];
+        new_value.op = Some("log2()".to_string());
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Log2));
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            let x = value.borrow().prev[0].clone();
+            vec![grad.clone() / (x * T::LN_2)]
         });
 
         Value::new(new_value)
     }
-}
-
-impl Div for Value {
-    type Output = Self;
 
-    fn div(self, other: Self) -> Self {
-        let mut new_value = ValueData::new(self.borrow().data / other.borrow().data);
+    pub fn sqrt(&self) -> Self {
+        let mut new_value = ValueData::new(Rule::Sqrt.forward(&[self.borrow().data]));
 
-        new_value.prev = vec![self, other];
-        new_value.op = Some(String::from("/"));
-        new_value.backward = Some(|value: &ValueData| {
-            value.prev[0].borrow_mut().grad += value.grad / value.prev[1].borrow().data;
-            value.prev[1].borrow_mut().grad +=
-                value.grad * value.prev[0].borrow().data / value.prev[1].borrow().data.powi(2);
+        new_value.prev = vec![self.clone()];
+        new_value.op = Some("sqrt()".to_string());
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Sqrt));
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            vec![grad.clone() / (value.clone() * T::from_f64(2.0))]
         });
 
         Value::new(new_value)
     }
-}
 
-impl Sum for Value {
-    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
-        let mut new_value = ValueData::new(0.0);
+    pub fn sigmoid(&self) -> Self {
+        let mut new_value = ValueData::new(Rule::Sigmoid.forward(&[self.borrow().data]));
 
-        let sum: f64 = iter
-            .map(|val| {
-                new_value.prev.push(val.clone());
-                val.borrow().data
-            })
-            .sum();
-        new_value.data = sum;
+        new_value.prev = vec![self.clone()];
+        new_value.op = Some("sigmoid()".to_string());
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Sigmoid));
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            vec![grad.clone() * value.clone() * value.clone().rsub(T::ONE)]
+        });
 
-        new_value.op = Some(String::from("+"));
-        new_value.backward = Some(|value: &ValueData| {
-            for val in value.prev.iter() {
-                val.borrow_mut().grad += value.grad;
-            }
+        Value::new(new_value)
+    }
+
+    /// `ln(1 + e^x)`, computed in a way that doesn't overflow for large inputs.
+    pub fn softplus(&self) -> Self {
+        let mut new_value = ValueData::new(Rule::Softplus.forward(&[self.borrow().data]));
+
+        new_value.prev = vec![self.clone()];
+        new_value.op = Some("softplus()".to_string());
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Softplus));
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            let x = value.borrow().prev[0].clone();
+            vec![grad.clone() * x.sigmoid()]
         });
 
         Value::new(new_value)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    pub fn sin(&self) -> Self {
+        let mut new_value = ValueData::new(Rule::Sin.forward(&[self.borrow().data]));
 
-    #[test]
-    fn final_grad() {
-        let a = Value::from(3.0);
-        assert_eq!(a.borrow().grad, 0.0);
-        a.backward();
-        assert_eq!(a.borrow().grad, 1.0);
+        new_value.prev = vec![self.clone()];
+        new_value.op = Some("sin()".to_string());
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Sin));
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            let x = value.borrow().prev[0].clone();
+            vec![grad.clone() * x.cos()]
+        });
+
+        Value::new(new_value)
     }
 
-    #[test]
-    fn add() {
-        let a = Value::from(3.0);
-        let b = Value::from(4.0);
-        let c = a.clone() + b.clone();
+    pub fn cos(&self) -> Self {
+        let mut new_value = ValueData::new(Rule::Cos.forward(&[self.borrow().data]));
 
-        c.backward();
+        new_value.prev = vec![self.clone()];
+        new_value.op = Some("cos()".to_string());
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Cos));
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            let x = value.borrow().prev[0].clone();
+            vec![-grad.clone() * x.sin()]
+        });
 
-        assert_eq!(c.borrow().data, 7.0);
-        assert_eq!(a.borrow().grad, 1.0);
-        assert_eq!(b.borrow().grad, 1.0);
+        Value::new(new_value)
     }
 
-    #[test]
-    fn add_self() {
-        let a = Value::from(3.0);
-        let b = a.clone() + a.clone();
+    /// The absolute value. Its gradient at zero is taken to be `0`.
+    pub fn abs(&self) -> Self {
+        let mut new_value = ValueData::new(Rule::Abs.forward(&[self.borrow().data]));
 
-        b.backward();
+        new_value.prev = vec![self.clone()];
+        new_value.op = Some("abs()".to_string());
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Abs));
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            let x = value.borrow().prev[0].borrow().data;
+            vec![grad.clone() * sign(x)]
+        });
 
-        assert_eq!(b.borrow().data, 6.0);
-        assert_eq!(a.borrow().grad, 2.0);
+        Value::new(new_value)
     }
 
-    #[test]
-    fn multiply() {
-        let a = Value::from(3.0);
-        let b = Value::from(4.0);
-        let c = a.clone() * b.clone();
+    /// Like `relu`, but negative inputs are scaled by `alpha` instead of zeroed.
+    pub fn leaky_relu(&self, alpha: T) -> Self {
+        let mut new_value = ValueData::new(Rule::LeakyRelu(alpha).forward(&[self.borrow().data]));
 
-        c.backward();
+        new_value.prev = vec![self.clone()];
+        new_value.op = Some("leaky_relu()".to_string());
+        new_value.constant = Some(alpha);
+        new_value.backward = Some(|value: &ValueData<T>| {
+            value.backward_rule(Rule::LeakyRelu(value.constant.unwrap()))
+        });
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            let x = value.borrow().prev[0].borrow().data;
+            let slope = if x > T::ZERO {
+                T::ONE
+            } else {
+                value.borrow().constant.unwrap()
+            };
+            vec![grad.clone() * slope]
+        });
 
-        assert_eq!(c.borrow().data, 12.0);
-        assert_eq!(a.borrow().grad, 4.0);
-        assert_eq!(b.borrow().grad, 3.0);
+        Value::new(new_value)
     }
 
-    // #[test]
-    // fn multiply_self() {
-    //     let a = Value::from(3.0);
-    //     let b = a.clone() * a.clone();
+    /// The exponential linear unit, `x` for positive inputs and `e^x - 1` otherwise.
+    pub fn elu(&self) -> Self {
+        let mut new_value = ValueData::new(Rule::Elu.forward(&[self.borrow().data]));
 
-    //     b.backward();
+        new_value.prev = vec![self.clone()];
+        new_value.op = Some("elu()".to_string());
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Elu));
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            let x = value.borrow().prev[0].borrow().data;
+            if x > T::ZERO {
+                vec![grad.clone()]
+            } else {
+                vec![grad.clone() * (value.clone() + T::ONE)]
+            }
+        });
 
-    //     assert_eq!(b.borrow().data, 9.0);
-    //     assert_eq!(a.borrow().grad, 6.0);
-    // }
+        Value::new(new_value)
+    }
 
-    #[test]
-    fn subtract() {
-        let a = Value::from(3.0);
-        let b = Value::from(4.0);
-        let c = a.clone() - b.clone();
+    /// The gaussian error linear unit, using the common tanh approximation
+    /// `0.5x(1 + tanh(sqrt(2/pi)(x + 0.044715x^3)))`.
+    pub fn gelu(&self) -> Self {
+        let mut new_value = ValueData::new(Rule::Gelu.forward(&[self.borrow().data]));
 
-        c.backward();
+        new_value.prev = vec![self.clone()];
+        new_value.op = Some("gelu()".to_string());
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Gelu));
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            let (c, k, half) = gelu_consts::<T>();
+            let three = T::from_f64(3.0);
+            let x = value.borrow().prev[0].clone();
+            let t = ((x.clone() + x.powi(3) * k) * c).tanh();
+            let slope = (t.clone() + T::ONE) * half
+                + x.clone()
+                    * t.powi(2).rsub(T::ONE)
+                    * (x.powi(2) * (three * k) + T::ONE)
+                    * (half * c);
+            vec![grad.clone() * slope]
+        });
 
-        assert_eq!(c.borrow().data, -1.0);
-        assert_eq!(a.borrow().grad, 1.0);
-        assert_eq!(b.borrow().grad, -1.0);
+        Value::new(new_value)
     }
 
-    #[test]
-    fn subtract_self() {
-        let a = Value::from(3.0);
-        let b = a.clone() - a.clone();
+    /// The sigmoid linear unit, `x * sigmoid(x)`.
+    pub fn silu(&self) -> Self {
+        let mut new_value = ValueData::new(Rule::Silu.forward(&[self.borrow().data]));
 
-        b.backward();
+        new_value.prev = vec![self.clone()];
+        new_value.op = Some("silu()".to_string());
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Silu));
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            let x = value.borrow().prev[0].clone();
+            let s = x.sigmoid();
+            vec![grad.clone() * s.clone() * (x * s.rsub(T::ONE) + T::ONE)]
+        });
 
-        assert_eq!(b.borrow().data, 0.0);
-        assert_eq!(a.borrow().grad, 0.0);
+        Value::new(new_value)
     }
 
-    #[test]
-    fn relu() {
-        // positive input
-        {
-            let a = Value::from(3.0);
-            let b = a.relu();
+    /// The larger of `self` and `other`. The gradient flows only to the chosen
+    /// operand, and ties choose `self`.
+    pub fn max(&self, other: &Value<T>) -> Self {
+        let mut new_value =
+            ValueData::new(Rule::Max.forward(&[self.borrow().data, other.borrow().data]));
+
+        new_value.prev = vec![self.clone(), other.clone()];
+        new_value.op = Some("max()".to_string());
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Max));
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            let a = value.borrow().prev[0].borrow().data;
+            let b = value.borrow().prev[1].borrow().data;
+            route_grad(grad, a >= b)
+        });
 
-            b.backward();
+        Value::new(new_value)
+    }
 
-            assert_eq!(b.borrow().data, 3.0);
-            assert_eq!(a.borrow().grad, 1.0);
-        }
+    /// The smaller of `self` and `other`. The gradient flows only to the chosen
+    /// operand, and ties choose `self`.
+    pub fn min(&self, other: &Value<T>) -> Self {
+        let mut new_value =
+            ValueData::new(Rule::Min.forward(&[self.borrow().data, other.borrow().data]));
+
+        new_value.prev = vec![self.clone(), other.clone()];
+        new_value.op = Some("min()".to_string());
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Min));
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            let a = value.borrow().prev[0].borrow().data;
+            let b = value.borrow().prev[1].borrow().data;
+            route_grad(grad, a <= b)
+        });
 
-        // negative input
-        {
-            let a = Value::from(-3.0);
-            let b = a.relu();
+        Value::new(new_value)
+    }
 
-            b.backward();
+    /// `max(self, lo)` for a constant `lo`. The gradient flows through when
+    /// `self >= lo`, including at the bound itself.
+    pub fn clamp_min(&self, lo: T) -> Self {
+        let mut new_value = ValueData::new(Rule::ClampMin(lo).forward(&[self.borrow().data]));
 
-            assert_eq!(b.borrow().data, 0.0);
-            assert_eq!(a.borrow().grad, 0.0);
+        new_value.prev = vec![self.clone()];
+        new_value.op = Some("clamp_min()".to_string());
+        new_value.constant = Some(lo);
+        new_value.backward = Some(|value: &ValueData<T>| {
+            value.backward_rule(Rule::ClampMin(value.constant.unwrap()))
+        });
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            let x = value.borrow().prev[0].borrow().data;
+            let slope = if x >= value.borrow().constant.unwrap() {
+                T::ONE
+            } else {
+                T::ZERO
+            };
+            vec![grad.clone() * slope]
+        });
+
+        Value::new(new_value)
+    }
+
+    /// `min(self, hi)` for a constant `hi`. The gradient flows through when
+    /// `self <= hi`, including at the bound itself.
+    pub fn clamp_max(&self, hi: T) -> Self {
+        let mut new_value = ValueData::new(Rule::ClampMax(hi).forward(&[self.borrow().data]));
+
+        new_value.prev = vec![self.clone()];
+        new_value.op = Some("clamp_max()".to_string());
+        new_value.constant = Some(hi);
+        new_value.backward = Some(|value: &ValueData<T>| {
+            value.backward_rule(Rule::ClampMax(value.constant.unwrap()))
+        });
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            let x = value.borrow().prev[0].borrow().data;
+            let slope = if x <= value.borrow().constant.unwrap() {
+                T::ONE
+            } else {
+                T::ZERO
+            };
+            vec![grad.clone() * slope]
+        });
+
+        Value::new(new_value)
+    }
+
+    /// Limits `self` to `lo..=hi`. The gradient flows through when `self` is within
+    /// the bounds, including at the bounds themselves.
+    pub fn clamp(&self, lo: T, hi: T) -> Self {
+        assert!(lo <= hi, "clamp bounds must satisfy lo <= hi");
+        self.clamp_min(lo).clamp_max(hi)
+    }
+
+    /// `a` if `cond` is true and `b` otherwise. The gradient flows only to the
+    /// chosen branch.
+    pub fn select(cond: bool, a: &Value<T>, b: &Value<T>) -> Self {
+        let mut new_value =
+            ValueData::new(Rule::Select(cond).forward(&[a.borrow().data, b.borrow().data]));
+
+        new_value.prev = vec![a.clone(), b.clone()];
+        new_value.op = Some("select()".to_string());
+        new_value.constant = Some(if cond { T::ONE } else { T::ZERO });
+        new_value.backward = Some(|value: &ValueData<T>| {
+            value.backward_rule(Rule::Select(value.constant.unwrap() != T::ZERO))
+        });
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            route_grad(grad, value.borrow().constant.unwrap() != T::ZERO)
+        });
+
+        Value::new(new_value)
+    }
+
+    // Comparisons of `data` that don't build any nodes, e.g. for `select`.
+
+    pub fn gt(&self, other: &Value<T>) -> bool {
+        self.borrow().data > other.borrow().data
+    }
+
+    pub fn ge(&self, other: &Value<T>) -> bool {
+        self.borrow().data >= other.borrow().data
+    }
+
+    pub fn lt(&self, other: &Value<T>) -> bool {
+        self.borrow().data < other.borrow().data
+    }
+
+    pub fn le(&self, other: &Value<T>) -> bool {
+        self.borrow().data <= other.borrow().data
+    }
+
+    /// Applies the built in op named `op` to `prev`, the inverse of reading a value's
+    /// `op`, `prev` and `constant`. Returns `None` for user defined ops and for inputs
+    /// that don't fit the op.
+    pub(crate) fn from_op(op: &str, prev: &[Value<T>], constant: Option<T>) -> Option<Value<T>> {
+        let value = match (op, prev, constant) {
+            ("+", [a], Some(c)) => a + c,
+            ("+", [a, b], None) => a + b,
+            ("+", prev, None) => prev.iter().sum(),
+            ("-", [a], Some(c)) => a - c,
+            ("-", [a, b], None) => a - b,
+            ("*", [a], Some(c)) => a * c,
+            ("*", [a, b], None) => a * b,
+            ("/", [a], Some(c)) => a / c,
+            ("/", [a, b], None) => a / b,
+            ("neg", [a], None) => -a,
+            ("rsub", [a], Some(c)) => a.clone().rsub(c),
+            ("rdiv", [a], Some(c)) => a.clone().rdiv(c),
+            ("pow()", [a, b], None) => a.pow(b.clone()),
+            ("powi()", [a], Some(c)) => a.powi(c.to_f64() as i32),
+            ("powf()", [a], Some(c)) => a.powf(c),
+            ("ReLU", [a], None) => a.relu(),
+            ("ln()", [a], None) => a.ln(),
+            ("tanh()", [a], None) => a.tanh(),
+            ("exp()", [a], None) => a.exp(),
+            ("log2()", [a], None) => a.log2(),
+            ("sqrt()", [a], None) => a.sqrt(),
+            ("sigmoid()", [a], None) => a.sigmoid(),
+            ("softplus()", [a], None) => a.softplus(),
+            ("sin()", [a], None) => a.sin(),
+            ("cos()", [a], None) => a.cos(),
+            ("abs()", [a], None) => a.abs(),
+            ("leaky_relu()", [a], Some(c)) => a.leaky_relu(c),
+            ("elu()", [a], None) => a.elu(),
+            ("gelu()", [a], None) => a.gelu(),
+            ("silu()", [a], None) => a.silu(),
+            ("max()", [a, b], None) => a.max(b),
+            ("min()", [a, b], None) => a.min(b),
+            ("clamp_min()", [a], Some(c)) => a.clamp_min(c),
+            ("clamp_max()", [a], Some(c)) => a.clamp_max(c),
+            ("select()", [a, b], Some(c)) => Value::select(c != T::ZERO, a, b),
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+/// One example of every form of op that `Value::from_op` builds, as its name, number
+/// of inputs and constant, so tests of code that reads ops back from a graph, such
+/// as `compile`, can cover all of them. Add new ops here as well.
+#[cfg(test)]
+pub(crate) const OP_EXAMPLES: &[(&str, usize, Option<f64>)] = &[
+    ("+", 1, Some(2.0)),
+    ("+", 2, None),
+    ("+", 3, None),
+    ("-", 1, Some(2.0)),
+    ("-", 2, None),
+    ("*", 1, Some(2.0)),
+    ("*", 2, None),
+    ("/", 1, Some(2.0)),
+    ("/", 2, None),
+    ("neg", 1, None),
+    ("rsub", 1, Some(2.0)),
+    ("rdiv", 1, Some(2.0)),
+    ("pow()", 2, None),
+    ("powi()", 1, Some(3.0)),
+    ("powf()", 1, Some(1.5)),
+    ("ReLU", 1, None),
+    ("ln()", 1, None),
+    ("tanh()", 1, None),
+    ("exp()", 1, None),
+    ("log2()", 1, None),
+    ("sqrt()", 1, None),
+    ("sigmoid()", 1, None),
+    ("softplus()", 1, None),
+    ("sin()", 1, None),
+    ("cos()", 1, None),
+    ("abs()", 1, None),
+    ("leaky_relu()", 1, Some(0.1)),
+    ("elu()", 1, None),
+    ("gelu()", 1, None),
+    ("silu()", 1, None),
+    ("max()", 2, None),
+    ("min()", 2, None),
+    ("clamp_min()", 1, Some(1.0)),
+    ("clamp_max()", 1, Some(1.0)),
+    ("select()", 2, Some(1.0)),
+    ("select()", 2, Some(0.0)),
+];
+
+/// The gradients of a two input op that passes its output gradient to only one of
+/// its inputs.
+fn route_grad<T: Float>(grad: &Value<T>, to_first: bool) -> Vec<Value<T>> {
+    if to_first {
+        vec![grad.clone(), Value::from(T::ZERO)]
+    } else {
+        vec![Value::from(T::ZERO), grad.clone()]
+    }
+}
+
+impl<T: Float> From<T> for Value<T> {
+    fn from(t: T) -> Value<T> {
+        Value::new(ValueData::new(t))
+    }
+}
+
+// Integers convert to the default `f64` values.
+macro_rules! from_int {
+    ($($t:ty),*) => {
+        $(
+            impl From<$t> for Value {
+                fn from(t: $t) -> Value {
+                    Value::from(f64::from(t))
+                }
+            }
+        )*
+    };
+}
+
+from_int!(i8, i16, i32, u8, u16, u32);
+
+impl<T: Float> Debug for Value<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let v = &self.borrow();
+        if let Some(label) = &v.label {
+            write!(f, "{} ", label)?;
+        }
+        write!(f, "data={} grad={}", v.data, v.grad)
+    }
+}
+
+impl<T: Float> Add for Value<T> {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        let mut new_value =
+            ValueData::new(Rule::Add.forward(&[self.borrow().data, other.borrow().data]));
+
+        new_value.prev = vec![self, other];
+        new_value.op = Some(String::from("+"));
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Add));
+        new_value.backward_graph =
+            Some(|_value: &Value<T>, grad: &Value<T>| vec![grad.clone(), grad.clone()]);
+
+        Value::new(new_value)
+    }
+}
+
+impl<T: Float> Sub for Value<T> {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        let mut new_value =
+            ValueData::new(Rule::Sub.forward(&[self.borrow().data, other.borrow().data]));
+
+        new_value.prev = vec![self, other];
+        new_value.op = Some(String::from("-"));
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Sub));
+        new_value.backward_graph =
+            Some(|_value: &Value<T>, grad: &Value<T>| vec![grad.clone(), -grad.clone()]);
+
+        Value::new(new_value)
+    }
+}
+
+impl<T: Float> Mul for Value<T> {
+    type Output = Self;
+
+    fn mul(self, other: Self) -> Self {
+        let mut new_value =
+            ValueData::new(Rule::Mul.forward(&[self.borrow().data, other.borrow().data]));
+
+        new_value.prev = vec![self, other];
+        new_value.op = Some(String::from("*"));
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Mul));
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            let a = value.borrow().prev[0].clone();
+            let b = value.borrow().prev[1].clone();
+            vec![grad.clone() * b, grad.clone() * a]
+        });
+
+        Value::new(new_value)
+    }
+}
+
+impl<T: Float> Div for Value<T> {
+    type Output = Self;
+
+    fn div(self, other: Self) -> Self {
+        let mut new_value =
+            ValueData::new(Rule::Div.forward(&[self.borrow().data, other.borrow().data]));
+
+        new_value.prev = vec![self, other];
+        new_value.op = Some(String::from("/"));
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Div));
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            let a = value.borrow().prev[0].clone();
+            let b = value.borrow().prev[1].clone();
+            vec![grad.clone() / b.clone(), -grad.clone() * a / b.powi(2)]
+        });
+
+        Value::new(new_value)
+    }
+}
+
+impl<T: Float> Neg for Value<T> {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        let mut new_value = ValueData::new(Rule::Neg.forward(&[self.borrow().data]));
+
+        new_value.prev = vec![self];
+        new_value.op = Some(String::from("neg"));
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Neg));
+        new_value.backward_graph = Some(|_value: &Value<T>, grad: &Value<T>| vec![-grad.clone()]);
+
+        Value::new(new_value)
+    }
+}
+
+// Arithmetic with constants of the value's float type. The constant is stored on the
+// resulting value instead of being wrapped in a `Value` of its own.
+
+impl<T: Float> Add<T> for Value<T> {
+    type Output = Value<T>;
+
+    fn add(self, other: T) -> Value<T> {
+        let mut new_value = ValueData::new(Rule::AddConst(other).forward(&[self.borrow().data]));
+
+        new_value.prev = vec![self];
+        new_value.op = Some(String::from("+"));
+        new_value.constant = Some(other);
+        new_value.backward = Some(|value: &ValueData<T>| {
+            value.backward_rule(Rule::AddConst(value.constant.unwrap()))
+        });
+        new_value.backward_graph = Some(|_value: &Value<T>, grad: &Value<T>| vec![grad.clone()]);
+
+        Value::new(new_value)
+    }
+}
+
+impl<T: Float> Sub<T> for Value<T> {
+    type Output = Value<T>;
+
+    fn sub(self, other: T) -> Value<T> {
+        let mut new_value = ValueData::new(Rule::SubConst(other).forward(&[self.borrow().data]));
+
+        new_value.prev = vec![self];
+        new_value.op = Some(String::from("-"));
+        new_value.constant = Some(other);
+        new_value.backward = Some(|value: &ValueData<T>| {
+            value.backward_rule(Rule::SubConst(value.constant.unwrap()))
+        });
+        new_value.backward_graph = Some(|_value: &Value<T>, grad: &Value<T>| vec![grad.clone()]);
+
+        Value::new(new_value)
+    }
+}
+
+impl<T: Float> Mul<T> for Value<T> {
+    type Output = Value<T>;
+
+    fn mul(self, other: T) -> Value<T> {
+        let mut new_value = ValueData::new(Rule::MulConst(other).forward(&[self.borrow().data]));
+
+        new_value.prev = vec![self];
+        new_value.op = Some(String::from("*"));
+        new_value.constant = Some(other);
+        new_value.backward = Some(|value: &ValueData<T>| {
+            value.backward_rule(Rule::MulConst(value.constant.unwrap()))
+        });
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            vec![grad.clone() * value.borrow().constant.unwrap()]
+        });
+
+        Value::new(new_value)
+    }
+}
+
+impl<T: Float> Div<T> for Value<T> {
+    type Output = Value<T>;
+
+    fn div(self, other: T) -> Value<T> {
+        let mut new_value = ValueData::new(Rule::DivConst(other).forward(&[self.borrow().data]));
+
+        new_value.prev = vec![self];
+        new_value.op = Some(String::from("/"));
+        new_value.constant = Some(other);
+        new_value.backward = Some(|value: &ValueData<T>| {
+            value.backward_rule(Rule::DivConst(value.constant.unwrap()))
+        });
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            vec![grad.clone() / value.borrow().constant.unwrap()]
+        });
+
+        Value::new(new_value)
+    }
+}
+
+impl<T: Float> Value<T> {
+    /// `other - self`, for when the constant is on the left.
+    fn rsub(self, other: T) -> Value<T> {
+        let mut new_value = ValueData::new(Rule::RSub(other).forward(&[self.borrow().data]));
+
+        new_value.prev = vec![self];
+        new_value.op = Some(String::from("rsub"));
+        new_value.constant = Some(other);
+        new_value.backward =
+            Some(|value: &ValueData<T>| value.backward_rule(Rule::RSub(value.constant.unwrap())));
+        new_value.backward_graph = Some(|_value: &Value<T>, grad: &Value<T>| vec![-grad.clone()]);
+
+        Value::new(new_value)
+    }
+
+    /// `other / self`, for when the constant is on the left.
+    fn rdiv(self, other: T) -> Value<T> {
+        let mut new_value = ValueData::new(Rule::RDiv(other).forward(&[self.borrow().data]));
+
+        new_value.prev = vec![self];
+        new_value.op = Some(String::from("rdiv"));
+        new_value.constant = Some(other);
+        new_value.backward =
+            Some(|value: &ValueData<T>| value.backward_rule(Rule::RDiv(value.constant.unwrap())));
+        new_value.backward_graph = Some(|value: &Value<T>, grad: &Value<T>| {
+            let x = value.borrow().prev[0].clone();
+            let c = value.borrow().constant.unwrap();
+            vec![-grad.clone() * c / x.powi(2)]
+        });
+
+        Value::new(new_value)
+    }
+}
+
+// Constants on the left are always `f64`, converted to the value's float type, so
+// that literals like `1.0 - x` work without knowing it.
+
+impl<T: Float> Add<Value<T>> for f64 {
+    type Output = Value<T>;
+
+    fn add(self, other: Value<T>) -> Value<T> {
+        other + T::from_f64(self)
+    }
+}
+
+impl<T: Float> Sub<Value<T>> for f64 {
+    type Output = Value<T>;
+
+    fn sub(self, other: Value<T>) -> Value<T> {
+        other.rsub(T::from_f64(self))
+    }
+}
+
+impl<T: Float> Mul<Value<T>> for f64 {
+    type Output = Value<T>;
+
+    fn mul(self, other: Value<T>) -> Value<T> {
+        other * T::from_f64(self)
+    }
+}
+
+impl<T: Float> Div<Value<T>> for f64 {
+    type Output = Value<T>;
+
+    fn div(self, other: Value<T>) -> Value<T> {
+        other.rdiv(T::from_f64(self))
+    }
+}
+
+impl<T: Float> Sum for Value<T> {
+    fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
+        let mut new_value = ValueData::new(T::ZERO);
+
+        let sum: T = iter
+            .map(|val| {
+                new_value.prev.push(val.clone());
+                val.borrow().data
+            })
+            .sum();
+        new_value.data = sum;
+
+        new_value.op = Some(String::from("+"));
+        new_value.backward = Some(|value: &ValueData<T>| value.backward_rule(Rule::Sum));
+        new_value.backward_graph =
+            Some(|value: &Value<T>, grad: &Value<T>| vec![grad.clone(); value.borrow().prev.len()]);
+
+        Value::new(new_value)
+    }
+}
+
+impl<'a, T: Float> Sum<&'a Value<T>> for Value<T> {
+    fn sum<I: Iterator<Item = &'a Value<T>>>(iter: I) -> Self {
+        iter.cloned().sum()
+    }
+}
+
+/// Multiplies the values together pairwise. The product of no values is `1`.
+impl<T: Float> Product for Value<T> {
+    fn product<I: Iterator<Item = Self>>(iter: I) -> Self {
+        iter.reduce(|product, val| product * val)
+            .unwrap_or_else(|| Value::from(T::ONE))
+    }
+}
+
+impl<'a, T: Float> Product<&'a Value<T>> for Value<T> {
+    fn product<I: Iterator<Item = &'a Value<T>>>(iter: I) -> Self {
+        iter.cloned().product()
+    }
+}
+
+// Implements a binary op for every mix of `Value`, `&Value` and constant operands by
+// cloning the referenced values, which only clones the `Rc`.
+macro_rules! forward_ref_binop {
+    ($imp:ident, $method:ident) => {
+        impl<T: Float> $imp<&Value<T>> for &Value<T> {
+            type Output = Value<T>;
+
+            fn $method(self, other: &Value<T>) -> Value<T> {
+                $imp::$method(self.clone(), other.clone())
+            }
+        }
+
+        impl<T: Float> $imp<Value<T>> for &Value<T> {
+            type Output = Value<T>;
+
+            fn $method(self, other: Value<T>) -> Value<T> {
+                $imp::$method(self.clone(), other)
+            }
+        }
+
+        impl<T: Float> $imp<&Value<T>> for Value<T> {
+            type Output = Value<T>;
+
+            fn $method(self, other: &Value<T>) -> Value<T> {
+                $imp::$method(self, other.clone())
+            }
+        }
+
+        impl<T: Float> $imp<T> for &Value<T> {
+            type Output = Value<T>;
+
+            fn $method(self, other: T) -> Value<T> {
+                $imp::$method(self.clone(), other)
+            }
+        }
+
+        impl<T: Float> $imp<&Value<T>> for f64 {
+            type Output = Value<T>;
+
+            fn $method(self, other: &Value<T>) -> Value<T> {
+                $imp::$method(self, other.clone())
+            }
+        }
+    };
+}
+
+forward_ref_binop!(Add, add);
+forward_ref_binop!(Sub, sub);
+forward_ref_binop!(Mul, mul);
+forward_ref_binop!(Div, div);
+
+impl<T: Float> Neg for &Value<T> {
+    type Output = Value<T>;
+
+    fn neg(self) -> Value<T> {
+        -self.clone()
+    }
+}
+
+// `a += b` rebinds `a` to a new value computed from the old one, which stays in the
+// graph as its input.
+macro_rules! assign_op {
+    ($imp:ident, $method:ident, $op:ident, $op_method:ident) => {
+        impl<T: Float> $imp<Value<T>> for Value<T> {
+            fn $method(&mut self, other: Value<T>) {
+                *self = $op::$op_method(self.clone(), other);
+            }
+        }
+
+        impl<T: Float> $imp<&Value<T>> for Value<T> {
+            fn $method(&mut self, other: &Value<T>) {
+                *self = $op::$op_method(self.clone(), other.clone());
+            }
+        }
+
+        impl<T: Float> $imp<T> for Value<T> {
+            fn $method(&mut self, other: T) {
+                *self = $op::$op_method(self.clone(), other);
+            }
+        }
+    };
+}
+
+assign_op!(AddAssign, add_assign, Add, add);
+assign_op!(SubAssign, sub_assign, Sub, sub);
+assign_op!(MulAssign, mul_assign, Mul, mul);
+assign_op!(DivAssign, div_assign, Div, div);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn final_grad() {
+        let a = Value::from(3.0);
+        assert_eq!(a.borrow().grad, 0.0);
+        a.backward();
+        assert_eq!(a.borrow().grad, 1.0);
+    }
+
+    #[test]
+    fn add() {
+        let a = Value::from(3.0);
+        let b = Value::from(4.0);
+        let c = a.clone() + b.clone();
+
+        c.backward();
+
+        assert_eq!(c.borrow().data, 7.0);
+        assert_eq!(a.borrow().grad, 1.0);
+        assert_eq!(b.borrow().grad, 1.0);
+    }
+
+    #[test]
+    fn add_self() {
+        let a = Value::from(3.0);
+        let b = a.clone() + a.clone();
+
+        b.backward();
+
+        assert_eq!(b.borrow().data, 6.0);
+        assert_eq!(a.borrow().grad, 2.0);
+    }
+
+    #[test]
+    fn multiply() {
+        let a = Value::from(3.0);
+        let b = Value::from(4.0);
+        let c = a.clone() * b.clone();
+
+        c.backward();
+
+        assert_eq!(c.borrow().data, 12.0);
+        assert_eq!(a.borrow().grad, 4.0);
+        assert_eq!(b.borrow().grad, 3.0);
+    }
+
+    #[test]
+    fn multiply_self() {
+        let a = Value::from(3.0);
+        let b = a.clone() * a.clone();
+
+        b.backward();
+
+        assert_eq!(b.borrow().data, 9.0);
+        assert_eq!(a.borrow().grad, 6.0);
+    }
+
+    #[test]
+    fn divide() {
+        let a = Value::from(3.0);
+        let b = Value::from(4.0);
+        let c = a.clone() / b.clone();
+
+        c.backward();
+
+        assert_eq!(c.borrow().data, 0.75);
+        assert_eq!(a.borrow().grad, 0.25);
+        assert_eq!(b.borrow().grad, -3.0 / 16.0);
+    }
+
+    #[test]
+    fn divide_self() {
+        let a = Value::from(3.0);
+        let b = a.clone() / a.clone();
+
+        b.backward();
+
+        assert_eq!(b.borrow().data, 1.0);
+        assert_eq!(a.borrow().grad, 0.0);
+    }
+
+    #[test]
+    fn subtract() {
+        let a = Value::from(3.0);
+        let b = Value::from(4.0);
+        let c = a.clone() - b.clone();
+
+        c.backward();
+
+        assert_eq!(c.borrow().data, -1.0);
+        assert_eq!(a.borrow().grad, 1.0);
+        assert_eq!(b.borrow().grad, -1.0);
+    }
+
+    #[test]
+    fn subtract_self() {
+        let a = Value::from(3.0);
+        let b = a.clone() - a.clone();
+
+        b.backward();
+
+        assert_eq!(b.borrow().data, 0.0);
+        assert_eq!(a.borrow().grad, 0.0);
+    }
+
+    #[test]
+    fn relu() {
+        // positive input
+        {
+            let a = Value::from(3.0);
+            let b = a.relu();
+
+            b.backward();
+
+            assert_eq!(b.borrow().data, 3.0);
+            assert_eq!(a.borrow().grad, 1.0);
+        }
+
+        // negative input
+        {
+            let a = Value::from(-3.0);
+            let b = a.relu();
+
+            b.backward();
+
+            assert_eq!(b.borrow().data, 0.0);
+            assert_eq!(a.borrow().grad, 0.0);
+        }
+    }
+
+    #[test]
+    fn grad_graph_matches_backward() {
+        let a = Value::from(3.0);
+        let b = Value::from(-2.0);
+        let c = Value::from(0.5);
+        let d = (a.clone() * b.clone() + c.clone()).tanh() * (a.clone() - c.clone())
+            + vec![a.clone(), b.clone().relu(), c.clone().pow(Value::from(3.0))]
+                .into_iter()
+                .sum::<Value>();
+
+        let grads = d.grad_graph(&[a.clone(), b.clone(), c.clone()]);
+        d.backward();
+
+        for (value, grad) in [a, b, c].iter().zip(grads.iter()) {
+            assert!((value.borrow().grad - grad.borrow().data).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn grad_graph_second_derivative() {
+        // d/dx x^3 = 3x^2, d^2/dx^2 x^3 = 6x
+        let x = Value::from(3.0);
+        let y = x.pow(Value::from(3.0));
+
+        let dy_dx = y.grad_graph(std::slice::from_ref(&x))[0].clone();
+        assert_eq!(dy_dx.borrow().data, 27.0);
+
+        dy_dx.backward();
+        assert_eq!(x.borrow().grad, 18.0);
+    }
+
+    #[test]
+    fn grad_graph_unrelated_value() {
+        let a = Value::from(3.0);
+        let b = Value::from(4.0);
+        let c = a.tanh();
+
+        let grads = c.grad_graph(&[b]);
+
+        assert_eq!(grads[0].borrow().data, 0.0);
+    }
+
+    #[test]
+    fn pow() {
+        let a = Value::from(3.0);
+        let b = Value::from(2.0);
+        let c = a.pow(b.clone());
+
+        c.backward();
+
+        assert_eq!(c.borrow().data, 9.0);
+        assert_eq!(a.borrow().grad, 6.0);
+        assert_eq!(b.borrow().grad, 3.0_f64.ln() * 9.0);
+    }
+
+    #[test]
+    fn pow_zero_base() {
+        let a = Value::from(0.0);
+        let b = Value::from(2.0);
+        let c = a.pow(b.clone());
+
+        c.backward();
+
+        assert_eq!(c.borrow().data, 0.0);
+        assert_eq!(a.borrow().grad, 0.0);
+        assert_eq!(b.borrow().grad, 0.0);
+    }
+
+    #[test]
+    fn pow_negative_base() {
+        let a = Value::from(-3.0);
+        let b: Value = Value::from(2.0);
+        let c = a.pow(b.clone());
+
+        c.backward();
+
+        assert_eq!(c.borrow().data, 9.0);
+        assert_eq!(a.borrow().grad, -6.0);
+        assert!(b.borrow().grad.is_nan());
+    }
+
+    #[test]
+    fn pow_grad_graph() {
+        let a = Value::from(2.0);
+        let b = Value::from(3.0);
+        let c = a.pow(b.clone());
+
+        let grads = c.grad_graph(&[a.clone(), b.clone()]);
+        c.backward();
+
+        assert_eq!(grads[0].borrow().data, a.borrow().grad);
+        assert_eq!(grads[1].borrow().data, b.borrow().grad);
+    }
+
+    #[test]
+    fn pow_self() {
+        // d/dx x^x = x^x * (ln(x) + 1)
+        let a = Value::from(2.0);
+        let b = a.pow(a.clone());
+
+        b.backward();
+
+        assert_eq!(b.borrow().data, 4.0);
+        assert!((a.borrow().grad - 4.0 * (2.0_f64.ln() + 1.0)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn deep_graph() {
+        let a = Value::from(1.0);
+        let mut b = a.clone();
+        for _ in 0..1_000_000 {
+            b += &a;
+        }
+
+        b.backward();
+
+        assert_eq!(b.borrow().data, 1_000_001.0);
+        assert_eq!(a.borrow().grad, 1_000_001.0);
+    }
+
+    /// Returns `(data, grad)` of `op` applied to `x`, checking that `grad_graph`
+    /// agrees with `backward`.
+    fn unary(x: f64, op: impl Fn(&Value) -> Value) -> (f64, f64) {
+        let a = Value::from(x);
+        let b = op(&a);
+
+        let graph_grad = b.grad_graph(std::slice::from_ref(&a))[0].borrow().data;
+        b.backward();
+
+        let grad = a.borrow().grad;
+        assert!((grad - graph_grad).abs() < 1e-12);
+        let data = b.borrow().data;
+        (data, grad)
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn exp() {
+        let (data, grad) = unary(2.0, |a| a.exp());
+        assert_close(data, 2.0_f64.exp());
+        assert_close(grad, 2.0_f64.exp());
+    }
+
+    #[test]
+    fn ln() {
+        let (data, grad) = unary(2.0, |a| a.ln());
+        assert_close(data, 2.0_f64.ln());
+        assert_close(grad, 0.5);
+    }
+
+    #[test]
+    fn log2() {
+        let (data, grad) = unary(8.0, |a| a.log2());
+        assert_close(data, 3.0);
+        assert_close(grad, 1.0 / (8.0 * std::f64::consts::LN_2));
+    }
+
+    #[test]
+    fn sqrt() {
+        let (data, grad) = unary(4.0, |a| a.sqrt());
+        assert_close(data, 2.0);
+        assert_close(grad, 0.25);
+    }
+
+    #[test]
+    fn sigmoid() {
+        let (data, grad) = unary(0.0, |a| a.sigmoid());
+        assert_close(data, 0.5);
+        assert_close(grad, 0.25);
+    }
+
+    #[test]
+    fn softplus() {
+        let (data, grad) = unary(1.0, |a| a.softplus());
+        assert_close(data, (1.0 + 1.0_f64.exp()).ln());
+        assert_close(grad, 1.0 / (1.0 + (-1.0_f64).exp()));
+
+        // large inputs don't overflow
+        let (data, grad) = unary(1000.0, |a| a.softplus());
+        assert_close(data, 1000.0);
+        assert_close(grad, 1.0);
+    }
+
+    #[test]
+    fn sin() {
+        let (data, grad) = unary(1.0, |a| a.sin());
+        assert_close(data, 1.0_f64.sin());
+        assert_close(grad, 1.0_f64.cos());
+    }
+
+    #[test]
+    fn cos() {
+        let (data, grad) = unary(1.0, |a| a.cos());
+        assert_close(data, 1.0_f64.cos());
+        assert_close(grad, -(1.0_f64.sin()));
+    }
+
+    #[test]
+    fn abs() {
+        assert_eq!(unary(-3.0, |a| a.abs()), (3.0, -1.0));
+        assert_eq!(unary(3.0, |a| a.abs()), (3.0, 1.0));
+        assert_eq!(unary(0.0, |a| a.abs()), (0.0, 0.0));
+    }
+
+    #[test]
+    fn leaky_relu() {
+        assert_eq!(unary(3.0, |a| a.leaky_relu(0.1)), (3.0, 1.0));
+        assert_eq!(unary(-3.0, |a| a.leaky_relu(0.5)), (-1.5, 0.5));
+    }
+
+    #[test]
+    fn elu() {
+        assert_eq!(unary(3.0, |a| a.elu()), (3.0, 1.0));
+
+        let (data, grad) = unary(-1.0, |a| a.elu());
+        assert_close(data, (-1.0_f64).exp() - 1.0);
+        assert_close(grad, (-1.0_f64).exp());
+    }
+
+    #[test]
+    fn gelu() {
+        let (data, grad) = unary(0.0, |a| a.gelu());
+        assert_close(data, 0.0);
+        assert_close(grad, 0.5);
+
+        // compare against a central difference
+        let h = 1e-6;
+        let f = |x: f64| unary(x, |a| a.gelu()).0;
+        let (data, grad) = unary(1.0, |a| a.gelu());
+        assert_close(data, 0.8411919906082768);
+        assert!((grad - (f(1.0 + h) - f(1.0 - h)) / (2.0 * h)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn silu() {
+        let (data, grad) = unary(0.0, |a| a.silu());
+        assert_close(data, 0.0);
+        assert_close(grad, 0.5);
+
+        let s = 1.0 / (1.0 + (-2.0_f64).exp());
+        let (data, grad) = unary(2.0, |a| a.silu());
+        assert_close(data, 2.0 * s);
+        assert_close(grad, s * (1.0 + 2.0 * (1.0 - s)));
+    }
+
+    #[test]
+    fn negate() {
+        let a = Value::from(3.0);
+        let b = -a.clone();
+
+        b.backward();
+
+        assert_eq!(b.borrow().data, -3.0);
+        assert_eq!(a.borrow().grad, -1.0);
+    }
+
+    #[test]
+    fn scalar_ops() {
+        assert_eq!(unary(3.0, |a| a.clone() + 2.0), (5.0, 1.0));
+        assert_eq!(unary(3.0, |a| 2.0 + a.clone()), (5.0, 1.0));
+        assert_eq!(unary(3.0, |a| a.clone() - 2.0), (1.0, 1.0));
+        assert_eq!(unary(3.0, |a| 2.0 - a.clone()), (-1.0, -1.0));
+        assert_eq!(unary(3.0, |a| a.clone() * 2.0), (6.0, 2.0));
+        assert_eq!(unary(3.0, |a| 2.0 * a.clone()), (6.0, 2.0));
+        assert_eq!(unary(3.0, |a| a.clone() / 2.0), (1.5, 0.5));
+        assert_eq!(unary(2.0, |a| 3.0 / a.clone()), (1.5, -0.75));
+    }
+
+    #[test]
+    fn scalar_ops_store_constant() {
+        let a = Value::from(3.0);
+        let b = a.clone() * 2.0;
+
+        assert_eq!(b.borrow().prev, vec![a]);
+        assert_eq!(b.borrow().constant, Some(2.0));
+    }
+
+    #[test]
+    fn powi() {
+        assert_eq!(unary(3.0, |a| a.powi(2)), (9.0, 6.0));
+        assert_eq!(unary(2.0, |a| a.powi(-1)), (0.5, -0.25));
+    }
+
+    #[test]
+    fn powf() {
+        assert_eq!(unary(4.0, |a| a.powf(0.5)), (2.0, 0.25));
+        assert_eq!(unary(3.0, |a| a.powf(2.0)), (9.0, 6.0));
+    }
+
+    #[test]
+    fn pow_zero_exponent_at_zero() {
+        assert_eq!(unary(0.0, |a| a.powi(0)), (1.0, 0.0));
+        assert_eq!(unary(0.0, |a| a.powf(0.0)), (1.0, 0.0));
+
+        let x = Value::from(0.0);
+        for y in [x.powi(0), x.powf(0.0)] {
+            let dy_dx = y.grad_graph(std::slice::from_ref(&x))[0].clone();
+            assert_eq!(dy_dx.borrow().data, 0.0);
+        }
+    }
+
+    #[test]
+    fn reference_ops() {
+        let a = Value::from(3.0);
+        let b = Value::from(4.0);
+        let c = &a * &b + &a / 2.0 - 1.0 * &b + -&a;
+
+        c.backward();
+
+        assert_eq!(c.borrow().data, 12.0 + 1.5 - 4.0 - 3.0);
+        assert_eq!(a.borrow().grad, 4.0 + 0.5 - 1.0);
+        assert_eq!(b.borrow().grad, 3.0 - 1.0);
+    }
+
+    #[test]
+    fn assign_ops() {
+        let a = Value::from(3.0);
+        let mut b = a.clone();
+        b += &a;
+        b *= 2.0;
+        b -= a.clone();
+        b /= &a;
+
+        b.backward();
+
+        // b = (2 * 2a - a) / a = 3
+        assert_eq!(b.borrow().data, 3.0);
+        assert_eq!(a.borrow().grad, 0.0);
+    }
+
+    #[test]
+    fn sum_references() {
+        let values = [Value::from(1.0), Value::from(2.0), Value::from(3.0)];
+        let sum: Value = values.iter().sum();
+
+        sum.backward();
+
+        assert_eq!(sum.borrow().data, 6.0);
+        for value in values.iter() {
+            assert_eq!(value.borrow().grad, 1.0);
+        }
+    }
+
+    #[test]
+    fn product() {
+        let values = [Value::from(2.0), Value::from(3.0), Value::from(4.0)];
+        let product: Value = values.iter().product();
+
+        product.backward();
+
+        assert_eq!(product.borrow().data, 24.0);
+        assert_eq!(values[0].borrow().grad, 12.0);
+        assert_eq!(values[1].borrow().grad, 8.0);
+        assert_eq!(values[2].borrow().grad, 6.0);
+
+        let empty: Value = Vec::<Value>::new().into_iter().product();
+        assert_eq!(empty.borrow().data, 1.0);
+    }
+
+    #[test]
+    fn no_grad_produces_leaves() {
+        let a = Value::from(3.0);
+        let b = Value::from(4.0);
+
+        let c = no_grad(|| (&a * &b).tanh() + 1.0);
+        assert_eq!(c.borrow().data, 12.0_f64.tanh() + 1.0);
+        assert!(c.borrow().prev.is_empty());
+        assert!(c.borrow().backward.is_none());
+
+        c.backward();
+        assert_eq!(a.borrow().grad, 0.0);
+
+        // graph building is back on afterwards
+        assert!(is_grad_enabled());
+        let d = &a * &b;
+        assert_eq!(d.borrow().prev.len(), 2);
+    }
+
+    #[test]
+    fn no_grad_nested() {
+        no_grad(|| {
+            no_grad(|| assert!(!is_grad_enabled()));
+            assert!(!is_grad_enabled());
+        });
+        assert!(is_grad_enabled());
+    }
+
+    #[test]
+    fn detach() {
+        let a = Value::from(3.0);
+        let target = (&a * 2.0).detach();
+        let loss = (&a - &target).powi(2);
+
+        loss.backward();
+
+        assert_eq!(target.borrow().data, 6.0);
+        assert!(target.borrow().prev.is_empty());
+        assert_eq!(a.borrow().grad, -6.0);
+    }
+
+    #[test]
+    fn zero_grad() {
+        let a = Value::from(3.0);
+        let b = (&a * 2.0).tanh();
+
+        b.backward();
+        b.zero_grad();
+
+        assert_eq!(a.borrow().grad, 0.0);
+        assert_eq!(b.borrow().grad, 0.0);
+    }
+
+    #[test]
+    fn hook_modifies_grad() {
+        let a = Value::from(3.0);
+        let b = &a * 2.0;
+        b.register_hook(|grad| grad * 10.0);
+        let c = b.tanh();
+
+        c.backward();
+
+        let expected = (1.0 - 6.0_f64.tanh().powi(2)) * 10.0;
+        assert_eq!(b.borrow().grad, expected);
+        assert_eq!(a.borrow().grad, expected * 2.0);
+    }
+
+    #[test]
+    fn hook_gradient_reversal() {
+        let a = Value::from(3.0);
+        let b = a.clone() + 0.0;
+        b.register_hook(|grad| -grad);
+        let c = &b * &b;
+
+        c.backward();
+
+        assert_eq!(a.borrow().grad, -6.0);
+    }
+
+    #[test]
+    fn hooks_see_each_pass() {
+        let a = Value::from(3.0);
+        a.register_hook(|grad| grad * 10.0);
+
+        (&a * 2.0).backward();
+        (&a * 3.0).backward();
+
+        // each pass's gradient is scaled once, not the running total
+        assert_eq!(a.borrow().grad, 50.0);
+    }
+
+    #[test]
+    fn hooks_observe_in_order() {
+        let seen = Rc::new(RefCell::new(vec![]));
+
+        let a = Value::from(3.0);
+        let b = &a * 4.0;
+        {
+            let seen = seen.clone();
+            b.register_hook(move |grad| {
+                seen.borrow_mut().push(grad);
+                grad + 1.0
+            });
+        }
+        {
+            let seen = seen.clone();
+            a.register_hook(move |grad| {
+                seen.borrow_mut().push(grad);
+                grad
+            });
+        }
+
+        b.backward();
+
+        assert_eq!(*seen.borrow(), vec![1.0, 8.0]);
+        assert_eq!(a.borrow().grad, 8.0);
+    }
+
+    #[test]
+    fn backward_leaf_grads() {
+        let a = Value::from(3.0);
+        let b = &a * 2.0;
+        let c = &b * 2.0;
+        c.retain_grad();
+        let d = c.tanh();
+
+        d.backward_leaf_grads();
+
+        let d_tanh = 1.0 - 12.0_f64.tanh().powi(2);
+        assert_eq!(a.borrow().grad, d_tanh * 4.0);
+        assert_eq!(b.borrow().grad, 0.0);
+        assert_eq!(c.borrow().grad, d_tanh);
+        assert_eq!(d.borrow().grad, 0.0);
+    }
+
+    #[test]
+    fn backward_and_free() {
+        let a = Value::from(3.0);
+        let b = &a * 2.0;
+        let c = b.tanh() + 1.0;
+
+        c.backward_and_free().unwrap();
+
+        let d_tanh = 1.0 - 6.0_f64.tanh().powi(2);
+        assert_eq!(a.borrow().grad, d_tanh * 2.0);
+        assert_eq!(c.borrow().data, 6.0_f64.tanh() + 1.0);
+        assert!(c.borrow().prev.is_empty());
+        assert!(!c.is_leaf());
+        assert!(a.is_leaf());
+
+        // a second pass can't reach `a` any more
+        assert_eq!(c.backward_and_free(), Err(GraphFreedError));
+        assert_eq!(a.borrow().grad, d_tanh * 2.0);
+    }
+
+    #[test]
+    fn backward_and_free_reclaims_memory() {
+        let a = Value::from(3.0);
+        let b = &a * 2.0;
+        let c = b.tanh();
+        let d = &c + &a;
+        let weak_b = Rc::downgrade(&b);
+        let weak_c = Rc::downgrade(&c);
+        drop(b);
+        drop(c);
+
+        // still held by `d`
+        assert!(weak_b.upgrade().is_some());
+
+        d.backward_and_free().unwrap();
+
+        assert!(weak_b.upgrade().is_none());
+        assert!(weak_c.upgrade().is_none());
+        assert_eq!(a.borrow().grad, (1.0 - 6.0_f64.tanh().powi(2)) * 2.0 + 1.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "freed by backward_and_free")]
+    fn backward_after_free_panics() {
+        let a = Value::from(3.0);
+        let b = &a * 2.0;
+        let c = &b + 1.0;
+
+        b.backward_and_free().unwrap();
+        c.backward();
+    }
+
+    #[test]
+    fn max_min() {
+        let a = Value::from(3.0);
+        let b = Value::from(4.0);
+        let c = a.max(&b) * 2.0 + a.min(&b);
+
+        c.backward();
+
+        assert_eq!(c.borrow().data, 11.0);
+        assert_eq!(a.borrow().grad, 1.0);
+        assert_eq!(b.borrow().grad, 2.0);
+    }
+
+    #[test]
+    fn max_min_ties() {
+        let a = Value::from(3.0);
+        let b = Value::from(3.0);
+        let c = a.max(&b) + a.min(&b);
+
+        c.backward();
+
+        assert_eq!(a.borrow().grad, 2.0);
+        assert_eq!(b.borrow().grad, 0.0);
+    }
+
+    #[test]
+    fn clamp() {
+        assert_eq!(unary(0.5, |a| a.clamp(-1.0, 1.0)), (0.5, 1.0));
+        assert_eq!(unary(3.0, |a| a.clamp(-1.0, 1.0)), (1.0, 0.0));
+        assert_eq!(unary(-3.0, |a| a.clamp(-1.0, 1.0)), (-1.0, 0.0));
+        assert_eq!(unary(1.0, |a| a.clamp(-1.0, 1.0)), (1.0, 1.0));
+        assert_eq!(unary(-1.0, |a| a.clamp(-1.0, 1.0)), (-1.0, 1.0));
+    }
+
+    #[test]
+    fn select() {
+        let a = Value::from(3.0);
+        let b = Value::from(4.0);
+        let c = Value::select(a.gt(&b), &a, &b) * 3.0;
+
+        c.backward();
+
+        assert_eq!(c.borrow().data, 12.0);
+        assert_eq!(a.borrow().grad, 0.0);
+        assert_eq!(b.borrow().grad, 3.0);
+    }
+
+    #[test]
+    fn comparisons() {
+        let a = Value::from(3.0);
+        let b = Value::from(4.0);
+
+        assert!(a.lt(&b) && a.le(&b) && !a.gt(&b) && !a.ge(&b));
+        assert!(a.le(&a) && a.ge(&a));
+    }
+
+    #[test]
+    fn hinge_loss() {
+        // max(0, 1 - y * pred)
+        let pred = Value::from(0.25);
+        let loss = (1.0 - &pred * -1.0).max(&Value::from(0.0));
+
+        let grads = loss.grad_graph(std::slice::from_ref(&pred));
+        loss.backward();
+
+        assert_eq!(loss.borrow().data, 1.25);
+        assert_eq!(pred.borrow().grad, 1.0);
+        assert_eq!(grads[0].borrow().data, 1.0);
+    }
+
+    #[test]
+    fn f32_values() {
+        let a = Value::from(3.0_f32);
+        let b = Value::from(4.0_f32);
+        let c = (&a * &b + 1.0 - a.powi(2)).tanh() * 2.0 + 1.0 / &b;
+
+        c.backward();
+
+        let a64 = Value::from(3.0);
+        let b64 = Value::from(4.0);
+        let c64 = (&a64 * &b64 + 1.0 - a64.powi(2)).tanh() * 2.0 + 1.0 / &b64;
+        c64.backward();
+
+        let data: f32 = c.borrow().data;
+        assert!((f64::from(data) - c64.borrow().data).abs() < 1e-6);
+        assert!((f64::from(a.borrow().grad) - a64.borrow().grad).abs() < 1e-6);
+        assert!((f64::from(b.borrow().grad) - b64.borrow().grad).abs() < 1e-6);
+    }
+
+    #[test]
+    fn labels() {
+        let a = Value::from(2.0).named("a");
+        let b = Value::from(-3.0);
+        let loss = (&a * &b).named("loss");
+
+        assert_eq!(a.borrow().label.as_deref(), Some("a"));
+        assert_eq!(b.borrow().label, None);
+        assert_eq!(format!("{:?}", loss), "loss data=-6 grad=0");
+        assert_eq!(format!("{:?}", b), "data=-3 grad=0");
+    }
+
+    #[test]
+    fn op_examples() {
+        for &(op, arity, constant) in OP_EXAMPLES {
+            let inputs: Vec<Value> = (0..arity).map(|i| Value::from(i as f64 + 0.5)).collect();
+            let value = Value::from_op(op, &inputs, constant).unwrap();
+            assert_eq!(value.borrow().op.as_deref(), Some(op));
+            assert_eq!(value.borrow().prev, inputs);
         }
     }
 }